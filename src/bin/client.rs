@@ -1,10 +1,18 @@
-use std::io::{self, Write, BufRead, BufReader};
-use std::net::TcpStream;
-use chrono;
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-use ruggine::protocol::ProtocolMessage;
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+
+use ruggine::common::{ChatMessage, GroupInvite, GroupRole, GroupVisibility, ReactionCount};
+use ruggine::protocol::{self, Envelope, ProtocolMessage, PROTOCOL_VERSION};
+use ruggine::tls::{self, ServerTrust};
 
 #[derive(PartialEq)]
 enum ClientState {
@@ -13,17 +21,50 @@ enum ClientState {
     InGroup(String),
 }
 
+/// Numero di messaggi richiesti per pagina da /history e /more
+const HISTORY_PAGE_SIZE: u32 = 20;
+
+/// Numero massimo di risultati richiesti da /search
+const SEARCH_RESULTS_LIMIT: u32 = 20;
+
+/// Caratteri dell'ID mostrati accanto a ogni messaggio, da usare con /edit, /delete, /reply e /thread
+const SHORT_ID_LENGTH: usize = 6;
+
+/// Caratteri del messaggio citato mostrati sopra una risposta
+const QUOTE_LENGTH: usize = 50;
+
 struct UserInterface {
     pub state: ClientState,
+    // Messaggio più vecchio mostrato nel gruppo corrente: cursore per /more
+    oldest_message_id: Option<String>,
+    has_more_history: bool,
+    // Funzionalità negoziate con il server durante l'handshake
+    capabilities: Vec<String>,
+    // Token della sessione corrente, usato per riprenderla senza password
+    session_token: Option<String>,
+    // Credenziali dell'ultimo login, usate se il token non è più valido dopo una riconnessione
+    credentials: Option<(String, String)>,
+    // Ultimo messaggio visto nel gruppo corrente: da qui si recuperano i messaggi persi
+    last_seen_message_id: Option<String>,
 }
 
 impl UserInterface {
     fn new() -> Self {
         Self {
             state: ClientState::NotAuthenticated,
+            oldest_message_id: None,
+            has_more_history: true,
+            capabilities: Vec::new(),
+            session_token: None,
+            credentials: None,
+            last_seen_message_id: None,
         }
     }
 
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
     fn show_welcome(&self) {
         println!("🚀 Welcome to Ruggine Chat!");
         println!("Type /help for available commands.");
@@ -47,9 +88,19 @@ impl UserInterface {
             }
             ClientState::Home => {
                 println!("\n📚 Available commands:");
-                println!("  /groups           - List your groups");
-                println!("  /create <name>    - Create a new group");
+                println!("  /groups [--archived] - List your groups (or the archived ones)");
+                println!("  /create [--private] <name> - Create a new group (private: invitation only)");
+                println!("  /browse           - List public groups");
                 println!("  /join <name>      - Join a group");
+                println!("  /search <terms>   - Search messages in your groups");
+                println!("  /invites          - List pending invitations");
+                println!("  /accept <group>   - Accept an invitation");
+                println!("  /decline <group>  - Decline an invitation");
+                println!("  /dm <user>        - Open a direct conversation");
+                println!("  /dms              - List your direct conversations");
+                println!("  /mentions         - List messages that mention you");
+                println!("  /unarchive <group> - Restore an archived group (owner only)");
+                println!("  /logout           - Log out and end the session");
                 println!("  /quit             - Exit application");
             }
             ClientState::InGroup(group_name) => {
@@ -58,6 +109,32 @@ impl UserInterface {
                 println!("  /quit-group       - Leave current group");
                 println!("  /invite <user>    - Invite user to group");
                 println!("  /users            - List group users");
+                println!("  /visibility <public|private> - Change who can join (admins)");
+                println!("  /rename <name>    - Rename the group (admins)");
+                println!("  /topic [text]     - Set or clear the topic (admins)");
+                println!("  /description [text] - Set or clear the description (admins)");
+                println!("  /archive          - Archive the group: read-only and hidden (owner only)");
+                println!("  /delete-group     - Delete the group and all its messages (owner only)");
+                println!("  /promote <user>   - Make a member admin (owner only)");
+                println!("  /demote <user>    - Remove admin role (owner only)");
+                println!("  /kick <user>      - Remove a member (admins)");
+                println!("  /ban <user> [time] - Ban a user, e.g. 30m, 2h, 7d (admins)");
+                println!("  /unban <user>     - Lift a ban (admins)");
+                println!("  /history [n]      - Show the last n messages");
+                println!("  /more             - Show older messages");
+                println!("  /search <terms>   - Search messages in this group");
+                println!("  /invites          - List pending invitations");
+                println!("  /accept <group>   - Accept an invitation");
+                println!("  /decline <group>  - Decline an invitation");
+                println!("  /dm <user>        - Open a direct conversation");
+                println!("  /dms              - List your direct conversations");
+                println!("  /mentions         - List messages that mention you");
+                println!("  /edit <id> <text> - Edit one of your messages (id shown next to it)");
+                println!("  /delete <id>      - Delete a message (yours, or any if admin)");
+                println!("  /reply <id> <text> - Reply to a message");
+                println!("  /thread <id>      - Show a message with all its replies");
+                println!("  /react <id> <emoji> - React to a message");
+                println!("  /unreact <id> <emoji> - Remove your reaction");
                 println!("  <message>         - Send message to group");
             }
         }
@@ -123,23 +200,75 @@ impl UserInterface {
                 match command {
                     "/help" => {
                         println!("📚 Available commands:");
-                        println!("  /groups           - List your groups");
-                        println!("  /create <name>    - Create a new group");
+                        println!("  /groups [--archived] - List your groups (or the archived ones)");
+                        println!("  /create [--private] <name> - Create a new group (private: invitation only)");
+                        println!("  /browse           - List public groups");
                         println!("  /join <name>      - Join a group");
+                        println!("  /search <terms>   - Search messages in your groups");
+                        println!("  /invites          - List pending invitations");
+                        println!("  /accept <group>   - Accept an invitation");
+                        println!("  /decline <group>  - Decline an invitation");
+                        println!("  /dm <user>        - Open a direct conversation");
+                        println!("  /dms              - List your direct conversations");
+                        println!("  /mentions         - List messages that mention you");
+                        println!("  /unarchive <group> - Restore an archived group (owner only)");
+                        println!("  /logout           - Log out and end the session");
                         println!("  /quit             - Exit application");
                         None
                     }
-                    "/groups" => Some(ProtocolMessage::ListGroups),
+                    "/groups" => match parts.get(1).map(|option| option.trim()) {
+                        None | Some("") => Some(ProtocolMessage::ListGroups),
+                        Some("--archived") => Some(ProtocolMessage::ListArchivedGroups),
+                        _ => {
+                            println!("❌ Usage: /groups [--archived]");
+                            None
+                        }
+                    },
+                    "/unarchive" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            Some(ProtocolMessage::UnarchiveGroup { group_name: parts[1].trim().to_string() })
+                        } else {
+                            println!("❌ Usage: /unarchive <group_name>");
+                            None
+                        }
+                    }
+                    "/invites" | "/accept" | "/decline" => Self::parse_invite_command(command, parts.get(1).copied()),
+                    "/dm" | "/dms" => Self::parse_direct_command(command, parts.get(1).copied()),
+                    "/mentions" => Some(ProtocolMessage::ListMentions),
+                    "/logout" => Some(ProtocolMessage::Logout),
+                    "/search" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            Some(ProtocolMessage::SearchMessages {
+                                query: parts[1].to_string(),
+                                group_name: None,
+                                limit: SEARCH_RESULTS_LIMIT,
+                            })
+                        } else {
+                            println!("❌ Usage: /search <terms>");
+                            None
+                        }
+                    }
                     "/create" => {
-                        if parts.len() == 2 {
+                        // "/create --private <name>" crea un gruppo in cui si entra solo su invito
+                        let (name, visibility) = match parts.get(1).map(|rest| rest.trim()) {
+                            Some("--private") => ("", GroupVisibility::Private),
+                            Some(rest) => match rest.split_once(char::is_whitespace) {
+                                Some(("--private", name)) => (name.trim(), GroupVisibility::Private),
+                                _ => (rest, GroupVisibility::Public),
+                            },
+                            None => ("", GroupVisibility::Public),
+                        };
+                        if !name.is_empty() {
                             Some(ProtocolMessage::CreateGroup {
-                                name: parts[1].to_string(),
+                                name: name.to_string(),
+                                visibility,
                             })
                         } else {
-                            println!("❌ Usage: /create <group_name>");
+                            println!("❌ Usage: /create [--private] <group_name>");
                             None
                         }
                     }
+                    "/browse" => Some(ProtocolMessage::ListPublicGroups),
                     "/join" => {
                         if parts.len() == 2 {
                             let group_name = parts[1].to_string();
@@ -165,6 +294,32 @@ impl UserInterface {
                         println!("  /quit-group       - Leave current group");
                         println!("  /invite <user>    - Invite user to group");
                         println!("  /users            - List group users");
+                        println!("  /visibility <public|private> - Change who can join (admins)");
+                        println!("  /rename <name>    - Rename the group (admins)");
+                        println!("  /topic [text]     - Set or clear the topic (admins)");
+                        println!("  /description [text] - Set or clear the description (admins)");
+                        println!("  /archive          - Archive the group: read-only and hidden (owner only)");
+                        println!("  /delete-group     - Delete the group and all its messages (owner only)");
+                        println!("  /promote <user>   - Make a member admin (owner only)");
+                        println!("  /demote <user>    - Remove admin role (owner only)");
+                        println!("  /kick <user>      - Remove a member (admins)");
+                        println!("  /ban <user> [time] - Ban a user, e.g. 30m, 2h, 7d (admins)");
+                        println!("  /unban <user>     - Lift a ban (admins)");
+                        println!("  /history [n]      - Show the last n messages");
+                        println!("  /more             - Show older messages");
+                        println!("  /search <terms>   - Search messages in this group");
+                        println!("  /invites          - List pending invitations");
+                        println!("  /accept <group>   - Accept an invitation");
+                        println!("  /decline <group>  - Decline an invitation");
+                        println!("  /dm <user>        - Open a direct conversation");
+                        println!("  /dms              - List your direct conversations");
+                        println!("  /mentions         - List messages that mention you");
+                        println!("  /edit <id> <text> - Edit one of your messages (id shown next to it)");
+                        println!("  /delete <id>      - Delete a message (yours, or any if admin)");
+                        println!("  /reply <id> <text> - Reply to a message");
+                        println!("  /thread <id>      - Show a message with all its replies");
+                        println!("  /react <id> <emoji> - React to a message");
+                        println!("  /unreact <id> <emoji> - Remove your reaction");
                         println!("  <message>         - Send message to group");
                         None
                     }
@@ -175,9 +330,8 @@ impl UserInterface {
                         Some(ProtocolMessage::GoHome)
                     }
                     "/quit-group" => {
-                        let group_name_clone = group_name.clone();
-                        self.state = ClientState::Home;
-                        Some(ProtocolMessage::LeaveGroup { group_name: group_name_clone })
+                        // Torniamo alla home solo quando il server conferma l'uscita
+                        Some(ProtocolMessage::LeaveGroup { group_name: group_name.clone() })
                     }
                     "/invite" => {
                         if parts.len() == 2 {
@@ -191,12 +345,217 @@ impl UserInterface {
                         }
                     }
                     "/users" => Some(ProtocolMessage::ListGroupUsers { group_name: group_name.clone() }),
+                    "/visibility" => match parts.get(1).map(|value| value.trim()) {
+                        Some("public") => Some(ProtocolMessage::SetGroupVisibility {
+                            group_name: group_name.clone(),
+                            visibility: GroupVisibility::Public,
+                        }),
+                        Some("private") => Some(ProtocolMessage::SetGroupVisibility {
+                            group_name: group_name.clone(),
+                            visibility: GroupVisibility::Private,
+                        }),
+                        _ => {
+                            println!("❌ Usage: /visibility <public|private>");
+                            None
+                        }
+                    },
+                    "/rename" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            Some(ProtocolMessage::RenameGroup {
+                                group_name: group_name.clone(),
+                                new_name: parts[1].trim().to_string(),
+                            })
+                        } else {
+                            println!("❌ Usage: /rename <new_name>");
+                            None
+                        }
+                    }
+                    "/edit" => {
+                        let arguments: Vec<&str> = parts.get(1).map(|rest| rest.trim().splitn(2, ' ').collect()).unwrap_or_default();
+                        if arguments.len() == 2 && !arguments[1].trim().is_empty() {
+                            Some(ProtocolMessage::EditMessage {
+                                message_id: arguments[0].to_string(),
+                                content: arguments[1].trim().to_string(),
+                            })
+                        } else {
+                            println!("❌ Usage: /edit <message_id> <new text>");
+                            None
+                        }
+                    }
+                    "/reply" => {
+                        let arguments: Vec<&str> = parts.get(1).map(|rest| rest.trim().splitn(2, ' ').collect()).unwrap_or_default();
+                        if arguments.len() == 2 && !arguments[1].trim().is_empty() {
+                            Some(ProtocolMessage::SendMessage {
+                                content: arguments[1].trim().to_string(),
+                                group_name: group_name.clone(),
+                                reply_to: Some(arguments[0].to_string()),
+                            })
+                        } else {
+                            println!("❌ Usage: /reply <message_id> <text>");
+                            None
+                        }
+                    }
+                    "/react" | "/unreact" => {
+                        let arguments: Vec<&str> = parts.get(1).map(|rest| rest.split_whitespace().collect()).unwrap_or_default();
+                        if arguments.len() == 2 {
+                            let message_id = arguments[0].to_string();
+                            let emoji = arguments[1].to_string();
+                            if command == "/react" {
+                                Some(ProtocolMessage::React { message_id, emoji })
+                            } else {
+                                Some(ProtocolMessage::Unreact { message_id, emoji })
+                            }
+                        } else {
+                            println!("❌ Usage: {} <message_id> <emoji>", command);
+                            None
+                        }
+                    }
+                    "/thread" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            Some(ProtocolMessage::FetchThread { message_id: parts[1].trim().to_string() })
+                        } else {
+                            println!("❌ Usage: /thread <message_id>");
+                            None
+                        }
+                    }
+                    "/delete" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            Some(ProtocolMessage::DeleteMessage { message_id: parts[1].trim().to_string() })
+                        } else {
+                            println!("❌ Usage: /delete <message_id>");
+                            None
+                        }
+                    }
+                    "/archive" => Some(ProtocolMessage::ArchiveGroup { group_name: group_name.clone() }),
+                    "/delete-group" => {
+                        // L'eliminazione non si può annullare: serve ripetere il nome del gruppo
+                        match parts.get(1).map(|name| name.trim()) {
+                            Some(name) if name == group_name => Some(ProtocolMessage::DeleteGroup { group_name: group_name.clone() }),
+                            Some(name) if !name.is_empty() => {
+                                println!("❌ '{}' is not the name of this group: nothing was deleted", name);
+                                None
+                            }
+                            _ => {
+                                println!("⚠️ This permanently deletes '{}' with all its messages.", group_name);
+                                println!("Type /delete-group {} to confirm.", group_name);
+                                None
+                            }
+                        }
+                    }
+                    "/topic" | "/description" => {
+                        // Senza testo il campo viene rimosso
+                        let text = parts.get(1).map(|text| text.trim().to_string()).filter(|text| !text.is_empty());
+                        if command == "/topic" {
+                            Some(ProtocolMessage::SetGroupTopic { group_name: group_name.clone(), topic: text })
+                        } else {
+                            Some(ProtocolMessage::SetGroupDescription { group_name: group_name.clone(), description: text })
+                        }
+                    }
+                    "/promote" | "/demote" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            let username = parts[1].trim().to_string();
+                            let group_name = group_name.clone();
+                            if command == "/promote" {
+                                Some(ProtocolMessage::PromoteMember { group_name, username })
+                            } else {
+                                Some(ProtocolMessage::DemoteMember { group_name, username })
+                            }
+                        } else {
+                            println!("❌ Usage: {} <username>", command);
+                            None
+                        }
+                    }
+                    "/kick" | "/unban" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            let username = parts[1].trim().to_string();
+                            let group_name = group_name.clone();
+                            if command == "/kick" {
+                                Some(ProtocolMessage::KickMember { group_name, username })
+                            } else {
+                                Some(ProtocolMessage::UnbanMember { group_name, username })
+                            }
+                        } else {
+                            println!("❌ Usage: {} <username>", command);
+                            None
+                        }
+                    }
+                    "/ban" => {
+                        let arguments: Vec<&str> = parts.get(1).map(|rest| rest.split_whitespace().collect()).unwrap_or_default();
+                        let duration_secs = match arguments.get(1) {
+                            Some(duration) => match parse_duration(duration) {
+                                Some(secs) => Some(secs),
+                                None => {
+                                    println!("❌ Invalid duration '{}': use e.g. 90s, 30m, 2h or 7d", duration);
+                                    return None;
+                                }
+                            },
+                            None => None,
+                        };
+                        if arguments.len() == 1 || arguments.len() == 2 {
+                            Some(ProtocolMessage::BanMember {
+                                group_name: group_name.clone(),
+                                username: arguments[0].to_string(),
+                                duration_secs,
+                            })
+                        } else {
+                            println!("❌ Usage: /ban <username> [duration]");
+                            None
+                        }
+                    }
+                    "/invites" | "/accept" | "/decline" => Self::parse_invite_command(command, parts.get(1).copied()),
+                    "/dm" | "/dms" => Self::parse_direct_command(command, parts.get(1).copied()),
+                    "/mentions" => Some(ProtocolMessage::ListMentions),
+                    "/history" => {
+                        let limit = if parts.len() == 2 {
+                            match parts[1].trim().parse::<u32>() {
+                                Ok(limit) if limit > 0 => limit,
+                                _ => {
+                                    println!("❌ Usage: /history [number_of_messages]");
+                                    return None;
+                                }
+                            }
+                        } else {
+                            HISTORY_PAGE_SIZE
+                        };
+                        Some(ProtocolMessage::FetchHistory {
+                            group_name: group_name.clone(),
+                            before: None,
+                            limit,
+                            after: None,
+                        })
+                    }
+                    "/search" => {
+                        if parts.len() == 2 && !parts[1].trim().is_empty() {
+                            Some(ProtocolMessage::SearchMessages {
+                                query: parts[1].to_string(),
+                                group_name: Some(group_name.clone()),
+                                limit: SEARCH_RESULTS_LIMIT,
+                            })
+                        } else {
+                            println!("❌ Usage: /search <terms>");
+                            None
+                        }
+                    }
+                    "/more" => {
+                        if !self.has_more_history {
+                            println!("📭 No older messages in this group.");
+                            None
+                        } else {
+                            Some(ProtocolMessage::FetchHistory {
+                                group_name: group_name.clone(),
+                                before: self.oldest_message_id.clone(),
+                                limit: HISTORY_PAGE_SIZE,
+                                after: None,
+                            })
+                        }
+                    }
                     "/quit" => Some(ProtocolMessage::Quit),
                     _ => {
                         // Messaggio normale
                         Some(ProtocolMessage::SendMessage {
                             content: input.to_string(),
                             group_name: group_name.clone(),
+                            reply_to: None,
                         })
                     }
                 }
@@ -204,11 +563,39 @@ impl UserInterface {
         }
     }
 
+    /// Comandi per gli inviti, disponibili sia nella home sia dentro un gruppo
+    fn parse_invite_command(command: &str, argument: Option<&str>) -> Option<ProtocolMessage> {
+        let group_name = argument.map(str::trim).filter(|group| !group.is_empty()).map(str::to_string);
+        match (command, group_name) {
+            ("/invites", _) => Some(ProtocolMessage::ListInvites),
+            ("/accept", Some(group_name)) => Some(ProtocolMessage::AcceptInvite { group_name }),
+            ("/decline", Some(group_name)) => Some(ProtocolMessage::DeclineInvite { group_name }),
+            _ => {
+                println!("❌ Usage: {} <group_name>", command);
+                None
+            }
+        }
+    }
+
+    /// Comandi per le conversazioni dirette, disponibili sia nella home sia dentro un gruppo
+    fn parse_direct_command(command: &str, argument: Option<&str>) -> Option<ProtocolMessage> {
+        let username = argument.map(|user| user.trim().trim_start_matches('@')).filter(|user| !user.is_empty());
+        match (command, username) {
+            ("/dms", _) => Some(ProtocolMessage::ListDirects),
+            ("/dm", Some(username)) => Some(ProtocolMessage::OpenDirect { username: username.to_string() }),
+            _ => {
+                println!("❌ Usage: /dm <username>");
+                None
+            }
+        }
+    }
+
     fn handle_response(&mut self, response: ProtocolMessage) -> Option<Vec<ruggine::common::ChatMessage>> {
         match response {
-            ProtocolMessage::AuthResult { success, message, .. } => {
+            ProtocolMessage::AuthResult { success, message, session_token, .. } => {
                 if success {
                     println!("✅ {}", message);
+                    self.session_token = session_token;
                     self.state = ClientState::Home;
                     // Mostra automaticamente i comandi disponibili dopo il login/registrazione
                     self.show_available_commands();
@@ -221,15 +608,56 @@ impl UserInterface {
                 if groups.is_empty() {
                     println!("📭 You are not in any groups");
                 } else {
-                    println!("📋 Your groups:");
+                    if groups.iter().all(|group| group.archived) {
+                        println!("🗄️ Archived groups:");
+                    } else {
+                        println!("📋 Your groups:");
+                    }
                     for group in groups {
-                        println!("  • {}", group.name);
+                        let unread = if group.unread_count > 0 { format!(" ({} unread)", group.unread_count) } else { String::new() };
+                        match group.visibility {
+                            GroupVisibility::Public => println!("  • {}{}", group.name, unread),
+                            GroupVisibility::Private => println!("  • {}{} 🔒", group.name, unread),
+                        }
                     }
                 }
                 None
             }
+            ProtocolMessage::SessionResumed { group, recent_messages, .. } => {
+                println!("🔄 Session resumed");
+                match group {
+                    Some(group) => {
+                        println!("✅ Back in group '{}'", group.name);
+                        self.state = ClientState::InGroup(group.name);
+                        self.last_seen_message_id = recent_messages.last().map(|m| m.id.clone());
+                        self.oldest_message_id = recent_messages.first().map(|m| m.id.clone());
+                        self.has_more_history = self.oldest_message_id.is_some();
+                        self.show_available_commands();
+                        Some(recent_messages)
+                    }
+                    None => {
+                        self.state = ClientState::Home;
+                        self.show_available_commands();
+                        None
+                    }
+                }
+            }
             ProtocolMessage::GroupJoined { group, recent_messages } => {
-                println!("✅ Entered group '{}'!", group.name);
+                match group.name.strip_prefix('@') {
+                    Some(username) => println!("💬 Direct conversation with {}", username),
+                    None => println!("✅ Entered group '{}'!", group.name),
+                }
+                if let Some(topic) = &group.topic {
+                    println!("📌 Topic: {}", topic);
+                }
+                if group.archived {
+                    println!("🗄️ This group is archived: you can read it but not write");
+                }
+                self.state = ClientState::InGroup(group.name);
+                self.last_seen_message_id = recent_messages.last().map(|m| m.id.clone());
+                self.oldest_message_id = recent_messages.first().map(|m| m.id.clone());
+                self.has_more_history = self.oldest_message_id.is_some();
+                self.show_available_commands();
                 // Restituisce i messaggi per mostrarli dopo i comandi
                 Some(recent_messages)
             }
@@ -240,6 +668,17 @@ impl UserInterface {
                 }
                 None
             }
+            ProtocolMessage::GroupMemberList { group_name, members } => {
+                println!("👥 Members of '{}':", group_name);
+                for member in members {
+                    match member.role {
+                        GroupRole::Owner => println!("  👑 {} (owner)", member.username),
+                        GroupRole::Admin => println!("  ⭐ {} (admin)", member.username),
+                        GroupRole::Member => println!("  • {}", member.username),
+                    }
+                }
+                None
+            }
             ProtocolMessage::Ok { message } => {
                 println!("✅ {}", message);
                 None
@@ -259,6 +698,184 @@ impl UserInterface {
                 println!("📬 New messages received!");
                 Some(recent_messages)
             }
+            ProtocolMessage::HistoryPage { messages, has_more } => {
+                if let Some(first) = messages.first() {
+                    self.oldest_message_id = Some(first.id.clone());
+                }
+                self.has_more_history = has_more;
+                self.show_history_page(&messages, has_more);
+                None
+            }
+            ProtocolMessage::Thread { group, messages } => {
+                if let Some((root, replies)) = messages.split_first() {
+                    println!("\n🧵 Thread in '{}':", group);
+                    println!("═══════════════════");
+                    println!("{}", Self::format_message(root));
+                    for reply in replies {
+                        // Le risposte dirette al messaggio iniziale non ripetono la citazione
+                        let line = if reply.reply_to.as_ref().is_some_and(|quoted| quoted.id == root.id) {
+                            Self::format_message(&ChatMessage { reply_to: None, ..reply.clone() })
+                        } else {
+                            Self::format_message(reply)
+                        };
+                        println!("  {}", line.replace('\n', "\n  "));
+                    }
+                    println!("═══════════════════");
+                    match replies.len() {
+                        0 => println!("No replies yet.\n"),
+                        1 => println!("1 reply\n"),
+                        count => println!("{} replies\n", count),
+                    }
+                }
+                None
+            }
+            ProtocolMessage::SearchResults { query, results } => {
+                self.show_search_results(&query, &results);
+                None
+            }
+            ProtocolMessage::MentionList { mentions } => {
+                if mentions.is_empty() {
+                    println!("📭 No unread mentions");
+                } else {
+                    println!("🔔 Unread mentions:");
+                    for mention in &mentions {
+                        println!("  [{}] {}", mention.group_name, Self::format_message(&mention.message).replace('\n', "\n  "));
+                    }
+                    println!("Use /join <group> to open the conversation.");
+                }
+                None
+            }
+            ProtocolMessage::Mentioned { group, message } => {
+                // Chi è già nel gruppo vede il messaggio con NewMessage
+                if self.state != ClientState::InGroup(group.clone()) {
+                    println!("\r🔔 {} mentioned you in '{}': {}", message.username, group, message.content);
+                }
+                None
+            }
+            ProtocolMessage::PublicGroupList { groups } => {
+                if groups.is_empty() {
+                    println!("📭 No public groups yet: create one with /create <name>");
+                } else {
+                    println!("🌍 Public groups:");
+                    for group in &groups {
+                        let members = if group.member_count == 1 { "1 member".to_string() } else { format!("{} members", group.member_count) };
+                        let joined = if group.is_member { ", joined" } else { "" };
+                        match &group.description {
+                            Some(description) => println!("  • {} ({}{}) - {}", group.name, members, joined, description),
+                            None => println!("  • {} ({}{})", group.name, members, joined),
+                        }
+                    }
+                    println!("Use /join <name> to enter one.");
+                }
+                None
+            }
+            ProtocolMessage::DirectList { conversations } => {
+                if conversations.is_empty() {
+                    println!("📭 No direct conversations: use /dm <user> to start one");
+                } else {
+                    println!("💬 Direct conversations:");
+                    for conversation in &conversations {
+                        match &conversation.last_message {
+                            Some(last) => println!(
+                                "  • @{} - {}",
+                                conversation.partner_username,
+                                Self::format_message(&ChatMessage { reply_to: None, ..last.clone() })
+                            ),
+                            None => println!("  • @{} (no messages yet)", conversation.partner_username),
+                        }
+                    }
+                    println!("Use /dm <user> to open one.");
+                }
+                None
+            }
+            ProtocolMessage::InviteList { invites } => {
+                if invites.is_empty() {
+                    println!("📭 No pending invitations");
+                } else {
+                    println!("📨 Pending invitations:");
+                    for invite in &invites {
+                        println!("  • {}", Self::format_invite(invite));
+                    }
+                    println!("Use /accept <group> or /decline <group> to answer.");
+                }
+                None
+            }
+            ProtocolMessage::InviteReceived { invite } => {
+                println!("\r📨 {}", Self::format_invite(&invite));
+                println!("Use /accept {} or /decline {} to answer.", invite.group_name, invite.group_name);
+                None
+            }
+            ProtocolMessage::RemovedFromGroup { group_name, removed_by, banned, banned_until } => {
+                if banned {
+                    let until = banned_until
+                        .map(|until| format!(" until {}", until.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")))
+                        .unwrap_or_default();
+                    println!("\r🚫 {} banned you from '{}'{}", removed_by, group_name, until);
+                } else {
+                    println!("\r👢 {} removed you from '{}'", removed_by, group_name);
+                }
+                self.leave_closed_group(group_name);
+                None
+            }
+            ProtocolMessage::GroupUpdated { previous_name, group } => {
+                if group.name != previous_name {
+                    println!("\r✏️ Group '{}' is now called '{}'", previous_name, group.name);
+                    // Il prompt e i comandi successivi devono usare il nuovo nome
+                    if self.state == ClientState::InGroup(previous_name) {
+                        self.state = ClientState::InGroup(group.name.clone());
+                    }
+                } else {
+                    println!("\r📝 Group '{}' was updated", group.name);
+                }
+                match &group.topic {
+                    Some(topic) => println!("📌 Topic: {}", topic),
+                    None => println!("📌 No topic"),
+                }
+                if let Some(description) = &group.description {
+                    println!("ℹ️ {}", description);
+                }
+                None
+            }
+            ProtocolMessage::MessageUpdated { group, message } => {
+                if self.state == ClientState::InGroup(group) {
+                    let change = if message.deleted { "🗑️" } else { "✏️" };
+                    println!("\r{} {}", change, Self::format_message(&message));
+                }
+                None
+            }
+            ProtocolMessage::ReactionsUpdated { group, message_id, reactions } => {
+                if self.state == ClientState::InGroup(group) {
+                    let id = &message_id[..message_id.len().min(SHORT_ID_LENGTH)];
+                    if reactions.is_empty() {
+                        println!("\r😶 No more reactions on {}", id);
+                    } else {
+                        println!("\r😀 Reactions on {}: {}", id, Self::format_reactions(&reactions));
+                    }
+                }
+                None
+            }
+            ProtocolMessage::GroupArchived { group_name, archived_by } => {
+                println!("\r🗄️ {} archived '{}': it is now read-only (see /groups --archived)", archived_by, group_name);
+                self.leave_closed_group(group_name);
+                None
+            }
+            ProtocolMessage::GroupDeleted { group_name, deleted_by } => {
+                println!("\r🗑️ {} deleted '{}'", deleted_by, group_name);
+                self.leave_closed_group(group_name);
+                None
+            }
+            ProtocolMessage::NewMessage { group, message } => {
+                // Aggiunge in coda solo il nuovo messaggio, senza ristampare la cronologia
+                if self.state == ClientState::InGroup(group.clone()) {
+                    self.last_seen_message_id = Some(message.id.clone());
+                    self.show_new_message(&message);
+                } else if group.starts_with('@') {
+                    println!("\r💬 New direct message from {}: {}", message.username, message.content);
+                } else {
+                    println!("📬 New message in group '{}'", group);
+                }
+                None
+            }
             _ => {
                 println!("{:?}", response);
                 None
@@ -271,82 +888,593 @@ impl UserInterface {
             println!("\n💬 Recent messages:");
             println!("═══════════════════");
             for message in messages {
-                // Formatta il timestamp per renderlo più leggibile
-                let timestamp = if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(&message.timestamp) {
-                    dt.format("%H:%M:%S").to_string()
+                println!("{}", Self::format_message(message));
+            }
+            println!("═══════════════════\n");
+        } else {
+            println!("📭 No recent messages in this group.\n");
+        }
+    }
+
+    fn show_history_page(&self, messages: &[ruggine::common::ChatMessage], has_more: bool) {
+        if messages.is_empty() {
+            println!("📭 No older messages in this group.");
+        } else {
+            println!("\n📜 History:");
+            println!("═══════════════════");
+            for message in messages {
+                println!("{}", Self::format_message(message));
+            }
+            println!("═══════════════════");
+            if has_more {
+                println!("Type /more to load older messages.");
+            }
+            println!();
+        }
+    }
+
+    fn show_search_results(&self, query: &str, results: &[ruggine::common::SearchResult]) {
+        if results.is_empty() {
+            println!("🔍 No messages found for '{}'.", query);
+        } else {
+            println!("\n🔍 Results for '{}':", query);
+            println!("═══════════════════");
+            for result in results {
+                let timestamp = if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(&result.message.timestamp) {
+                    dt.format("%Y-%m-%d %H:%M").to_string()
                 } else {
-                    message.timestamp.clone()
+                    result.message.timestamp.clone()
                 };
-                println!("[{}] {}: {}", timestamp, message.username, message.content);
+                // Evidenzia i termini trovati (grassetto giallo)
+                let snippet = result.snippet
+                    .replace(ruggine::common::SNIPPET_MATCH_START, "\x1b[1;33m")
+                    .replace(ruggine::common::SNIPPET_MATCH_END, "\x1b[0m");
+                println!("[{}] #{} {}: {}", timestamp, result.group_name, result.message.username, snippet);
             }
             println!("═══════════════════\n");
+        }
+    }
+
+    /// Torna nella home se ci si trovava nel gruppo da cui si è stati rimossi o che è stato chiuso
+    fn leave_closed_group(&mut self, group_name: String) {
+        if self.state == ClientState::InGroup(group_name) {
+            self.state = ClientState::Home;
+            self.last_seen_message_id = None;
+            self.show_available_commands();
+        }
+    }
+
+    fn show_new_message(&self, message: &ruggine::common::ChatMessage) {
+        // Sovrascrive il prompt corrente con il messaggio
+        print!("\r");
+        println!("{}", Self::format_message(message));
+    }
+
+    fn format_invite(invite: &GroupInvite) -> String {
+        let expiry = invite.expires_at
+            .map(|expires_at| format!(" (expires {})", expires_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")))
+            .unwrap_or_default();
+        format!("{} invited you to '{}'{}", invite.inviter_username, invite.group_name, expiry)
+    }
+
+    fn format_reactions(reactions: &[ReactionCount]) -> String {
+        reactions
+            .iter()
+            .map(|reaction| format!("{} {}", reaction.emoji, reaction.count))
+            .collect::<Vec<_>>()
+            .join("  ")
+    }
+
+    fn format_message(message: &ruggine::common::ChatMessage) -> String {
+        // Formatta il timestamp per renderlo più leggibile
+        let timestamp = if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(&message.timestamp) {
+            dt.format("%H:%M:%S").to_string()
         } else {
-            println!("📭 No recent messages in this group.\n");
+            message.timestamp.clone()
+        };
+        let id = &message.id[..message.id.len().min(SHORT_ID_LENGTH)];
+        let mut line = if message.deleted {
+            format!("[{} {}] {}: [deleted]", timestamp, id, message.username)
+        } else if message.edited_at.is_some() {
+            format!("[{} {}] {}: {} (edited)", timestamp, id, message.username, message.content)
+        } else {
+            format!("[{} {}] {}: {}", timestamp, id, message.username, message.content)
+        };
+        if !message.reactions.is_empty() {
+            line.push_str(&format!("  {}", Self::format_reactions(&message.reactions)));
+        }
+
+        // Le risposte mostrano sopra di sé l'inizio del messaggio citato
+        match &message.reply_to {
+            Some(quoted) => {
+                let quoted_text = match &quoted.content {
+                    Some(content) if content.chars().count() > QUOTE_LENGTH => {
+                        format!("{}…", content.chars().take(QUOTE_LENGTH).collect::<String>())
+                    }
+                    Some(content) => content.clone(),
+                    None => "[deleted]".to_string(),
+                };
+                format!("  ↪ {}: {}\n{}", quoted.username, quoted_text, line)
+            }
+            None => line,
         }
     }
 }
 
-struct ChatClient {
-    stream: TcpStream,
-    ui: UserInterface,
+/// Tempo massimo di attesa per la risposta a un comando
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Intervallo tra due Ping inviati al server per verificare la connessione
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Tempo massimo di attesa del Pong prima di considerare la connessione persa
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Attesa iniziale e massima tra due tentativi di riconnessione
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Numero massimo di messaggi persi recuperati dopo una riconnessione
+const MISSED_MESSAGES_LIMIT: u32 = 100;
+
+/// Connessione al server condivisa tra i thread del client.
+/// Lo stream è None mentre il client è offline e sta tentando di riconnettersi.
+struct Connection {
+    server_addr: String,
+    stream: Mutex<Option<Link>>,
+    tls: Option<TlsSettings>,
+    // Richieste in attesa di risposta: request_id -> canale su cui consegnare la risposta
+    pending: Mutex<HashMap<String, mpsc::Sender<ProtocolMessage>>>,
+    next_request_id: AtomicU64,
 }
 
-impl ChatClient {
-    fn new(stream: TcpStream) -> Result<Self, Box<dyn std::error::Error>> {
-        let ui = UserInterface::new();
-        
-        Ok(Self {
-            stream,
-            ui,
-        })
+impl Connection {
+    fn new(server_addr: String, tls: Option<TlsSettings>) -> Self {
+        Self {
+            server_addr,
+            stream: Mutex::new(None),
+            tls,
+            pending: Mutex::new(HashMap::new()),
+            next_request_id: AtomicU64::new(1),
+        }
     }
 
-    fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        use std::sync::{Arc, Mutex, mpsc};
-        use std::io::{BufReader, BufRead};
+    fn next_request_id(&self) -> String {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed).to_string()
+    }
+
+    fn is_online(&self) -> bool {
+        self.stream.lock().unwrap().is_some()
+    }
 
-        self.ui.show_welcome();
+    /// Invia un comando con un nuovo request_id e attende la risposta corrispondente
+    fn request(&self, message: &ProtocolMessage, timeout: Duration) -> Result<ProtocolMessage, Box<dyn std::error::Error>> {
+        let request_id = self.next_request_id();
 
-        let (tx_raw, rx_raw) = mpsc::channel::<ProtocolMessage>();
-        let stream_clone = self.stream.try_clone()?;
-        let ui = Arc::new(Mutex::new(std::mem::replace(&mut self.ui, UserInterface::new())));
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(request_id.clone(), tx);
 
-        // THREAD 1: Lettura socket -> tx_raw
-        {
-            let tx_raw = tx_raw.clone();
-            thread::spawn(move || {
-                let mut reader = BufReader::new(stream_clone);
-                loop {
-                    let mut line = String::new();
-                    if reader.read_line(&mut line).is_ok() {
-                        if let Ok(msg) = ProtocolMessage::from_wire_format(&line) {
-                            if tx_raw.send(msg).is_err() {
+        let envelope = Envelope::new(Some(request_id.clone()), message.clone());
+        if let Err(e) = self.send_envelope(&envelope) {
+            self.pending.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
+
+        let response = rx.recv_timeout(timeout);
+        self.pending.lock().unwrap().remove(&request_id);
+        response.map_err(|_| "No response from server".into())
+    }
+
+    /// Invia un messaggio senza attendere risposta
+    fn send_message(&self, message: &ProtocolMessage) -> Result<(), Box<dyn std::error::Error>> {
+        self.send_envelope(&Envelope::event(message.clone()))
+    }
+
+    fn send_envelope(&self, envelope: &Envelope) -> Result<(), Box<dyn std::error::Error>> {
+        let data = envelope.to_wire_format()?;
+        let mut stream = self.stream.lock().unwrap();
+        match stream.as_mut() {
+            Some(stream) => {
+                stream.write_all(data.as_bytes())?;
+                stream.flush()?;
+                Ok(())
+            }
+            None => Err("Offline: reconnecting to the server...".into()),
+        }
+    }
+
+    /// Chiude la connessione corrente: il thread di lettura avvierà la riconnessione
+    fn drop_connection(&self) {
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            stream.shutdown();
+        }
+        // Le richieste in attesa non riceveranno più risposta
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+/// Riga letta dal server, sia su TCP in chiaro sia su TLS
+type LineReader = BufReader<Box<dyn Read + Send>>;
+
+/// Parametri TLS usati a ogni connessione e riconnessione
+struct TlsSettings {
+    config: Arc<rustls::ClientConfig>,
+    // Nome atteso nel certificato del server (hostname o IP)
+    server_name: String,
+}
+
+/// Metà in scrittura di una connessione al server, in chiaro o cifrata con TLS
+struct Link {
+    writer: Box<dyn Write + Send>,
+    // Socket sottostante, usato per chiudere la connessione
+    socket: TcpStream,
+}
+
+impl Link {
+    fn shutdown(&self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+impl Write for Link {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Apre il socket verso il server e, se configurato, esegue l'handshake TLS
+fn open_link(server_addr: &str, tls: Option<&TlsSettings>) -> Result<(Link, LineReader), Box<dyn std::error::Error>> {
+    let socket = TcpStream::connect(server_addr)
+        .map_err(|e| format!("Cannot connect to {}: {}", server_addr, e))?;
+    match tls {
+        Some(tls) => {
+            let (reader, writer) = tls::connect(socket.try_clone()?, Arc::clone(&tls.config), &tls.server_name)
+                .map_err(|e| format!("TLS handshake with {} failed: {}", server_addr, e))?;
+            Ok((Link { writer: Box::new(writer), socket }, BufReader::new(Box::new(reader))))
+        }
+        None => {
+            let reader: Box<dyn Read + Send> = Box::new(socket.try_clone()?);
+            Ok((Link { writer: Box::new(socket.try_clone()?), socket }, BufReader::new(reader)))
+        }
+    }
+}
+
+/// Scambio sincrono richiesta/risposta su uno stream appena aperto, prima che il thread di lettura
+/// lo prenda in carico. Gli eventi ricevuti nel frattempo vengono inoltrati al thread degli eventi.
+fn exchange(
+    stream: &mut Link,
+    reader: &mut LineReader,
+    request_id: String,
+    message: ProtocolMessage,
+    events: &mpsc::Sender<ProtocolMessage>,
+) -> Result<ProtocolMessage, Box<dyn std::error::Error>> {
+    let data = Envelope::new(Some(request_id.clone()), message).to_wire_format()?;
+    stream.write_all(data.as_bytes())?;
+    stream.flush()?;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err("Server closed the connection".into());
+        }
+        let envelope = Envelope::from_wire_format(&line)?;
+        if envelope.request_id.as_deref() == Some(request_id.as_str()) {
+            return Ok(envelope.message);
+        }
+        let _ = events.send(envelope.message);
+    }
+}
+
+/// Esegue l'handshake Hello/Welcome e restituisce versione del server e funzionalità negoziate
+fn handshake(
+    stream: &mut Link,
+    reader: &mut LineReader,
+    request_id: String,
+    events: &mpsc::Sender<ProtocolMessage>,
+) -> Result<(String, Vec<String>), Box<dyn std::error::Error>> {
+    let hello = ProtocolMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: format!("ruggine-client/{}", env!("CARGO_PKG_VERSION")),
+        capabilities: protocol::capabilities::ALL.iter().map(|c| c.to_string()).collect(),
+    };
+    match exchange(stream, reader, request_id, hello, events)? {
+        ProtocolMessage::Welcome { server_version, capabilities } => Ok((server_version, capabilities)),
+        ProtocolMessage::Error { message } => Err(format!("Handshake rejected: {}", message).into()),
+        other => Err(format!("Unexpected handshake response: {:?}", other).into()),
+    }
+}
+
+/// Apre una connessione ed esegue l'handshake, salvando le funzionalità negoziate
+fn connect(
+    connection: &Connection,
+    ui: &Mutex<UserInterface>,
+    events: &mpsc::Sender<ProtocolMessage>,
+) -> Result<(Link, LineReader), Box<dyn std::error::Error>> {
+    let (mut stream, mut reader) = open_link(&connection.server_addr, connection.tls.as_ref())?;
+
+    let (server_version, capabilities) = handshake(&mut stream, &mut reader, connection.next_request_id(), events)?;
+    println!("🤝 Ruggine server v{} (features: {})", server_version,
+        if capabilities.is_empty() { "none".to_string() } else { capabilities.join(", ") });
+    ui.lock().unwrap().capabilities = capabilities;
+    Ok((stream, reader))
+}
+
+/// Dopo una riconnessione: autentica di nuovo l'utente (token di sessione o credenziali salvate),
+/// rientra nel gruppo in cui si trovava e mostra i messaggi persi mentre era offline
+fn restore_state(
+    connection: &Connection,
+    stream: &mut Link,
+    reader: &mut LineReader,
+    ui: &Mutex<UserInterface>,
+    events: &mpsc::Sender<ProtocolMessage>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (previous_group, token, credentials, last_seen) = {
+        let ui = ui.lock().unwrap();
+        let previous_group = match &ui.state {
+            ClientState::NotAuthenticated => return Ok(()),
+            ClientState::Home => None,
+            ClientState::InGroup(group) => Some(group.clone()),
+        };
+        (previous_group, ui.session_token.clone(), ui.credentials.clone(), ui.last_seen_message_id.clone())
+    };
+
+    // 1. Riprende la sessione con il token, altrimenti ripete il login
+    let mut current_group = None;
+    let mut authenticated = false;
+    if let Some(token) = token.filter(|_| ui.lock().unwrap().supports(protocol::capabilities::SESSIONS)) {
+        match exchange(stream, reader, connection.next_request_id(), ProtocolMessage::Resume { token }, events)? {
+            ProtocolMessage::SessionResumed { group, .. } => {
+                authenticated = true;
+                current_group = group.map(|g| g.name);
+            }
+            ProtocolMessage::AuthResult { message, .. } => println!("⚠️ {}", message),
+            other => println!("⚠️ Unexpected response to Resume: {:?}", other),
+        }
+    }
+    if !authenticated {
+        let (username, password) = credentials.ok_or("Session lost and no stored credentials: please log in again")?;
+        match exchange(stream, reader, connection.next_request_id(), ProtocolMessage::Login { username, password }, events)? {
+            ProtocolMessage::AuthResult { success: true, session_token, .. } => {
+                ui.lock().unwrap().session_token = session_token;
+            }
+            ProtocolMessage::AuthResult { message, .. } => return Err(message.into()),
+            other => return Err(format!("Unexpected response to Login: {:?}", other).into()),
+        }
+    }
+
+    // 2. Rientra nel gruppo in cui si trovava prima della disconnessione
+    if let Some(group_name) = previous_group.clone().filter(|g| current_group.as_ref() != Some(g)) {
+        match exchange(stream, reader, connection.next_request_id(), ProtocolMessage::JoinGroup { group_name: group_name.clone() }, events)? {
+            ProtocolMessage::GroupJoined { .. } => current_group = Some(group_name),
+            ProtocolMessage::Error { message } => println!("⚠️ Could not re-enter group '{}': {}", group_name, message),
+            other => println!("⚠️ Unexpected response to JoinGroup: {:?}", other),
+        }
+    }
+
+    // 3. Recupera i messaggi arrivati mentre eravamo offline
+    let mut missed = Vec::new();
+    let mut has_more = false;
+    if let (Some(group_name), Some(last_seen)) = (current_group.clone(), last_seen) {
+        if current_group == previous_group && ui.lock().unwrap().supports(protocol::capabilities::HISTORY) {
+            let fetch = ProtocolMessage::FetchHistory {
+                group_name,
+                before: None,
+                limit: MISSED_MESSAGES_LIMIT,
+                after: Some(last_seen),
+            };
+            if let ProtocolMessage::HistoryPage { messages, has_more: more } = exchange(stream, reader, connection.next_request_id(), fetch, events)? {
+                missed = messages;
+                has_more = more;
+            }
+        }
+    }
+
+    let mut ui = ui.lock().unwrap();
+    match current_group {
+        Some(group_name) => {
+            if previous_group.as_ref() != Some(&group_name) {
+                ui.oldest_message_id = None;
+                ui.has_more_history = true;
+            }
+            ui.state = ClientState::InGroup(group_name);
+        }
+        None => {
+            if previous_group.is_some() {
+                ui.show_available_commands();
+            }
+            ui.state = ClientState::Home;
+            ui.last_seen_message_id = None;
+        }
+    }
+    if let Some(last) = missed.last() {
+        ui.last_seen_message_id = Some(last.id.clone());
+        println!("📬 Messages received while offline:");
+        for message in &missed {
+            ui.show_new_message(message);
+        }
+        if has_more {
+            println!("… more messages were missed: use /history to see them.");
+        }
+    }
+    Ok(())
+}
+
+/// Thread di lettura: consegna le risposte a chi le attende e gli eventi al thread degli eventi.
+/// Quando la connessione cade, si riconnette con backoff esponenziale e ripristina lo stato.
+fn reader_loop(
+    connection: Arc<Connection>,
+    ui: Arc<Mutex<UserInterface>>,
+    mut reader: LineReader,
+    events: mpsc::Sender<ProtocolMessage>,
+) {
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                connection.drop_connection();
+                reader = reconnect(&connection, &ui, &events);
+            }
+            Ok(_) => {
+                if let Ok(Envelope { request_id, message }) = Envelope::from_wire_format(&line) {
+                    let waiter = request_id.and_then(|id| connection.pending.lock().unwrap().remove(&id));
+                    match waiter {
+                        // Chi attendeva potrebbe essere già andato in timeout: in quel caso la risposta si perde
+                        Some(waiter) => { let _ = waiter.send(message); }
+                        // Heartbeat del server: rispondiamo subito senza disturbare l'interfaccia
+                        None if matches!(message, ProtocolMessage::Ping) => {
+                            let _ = connection.send_message(&ProtocolMessage::Pong);
+                        }
+                        None => {
+                            if events.send(message).is_err() {
                                 break;
                             }
                         }
                     }
                 }
-            });
+            }
         }
+    }
+}
 
-        // THREAD 2: Stampa messaggi dal server in tempo reale
-        let ui_for_rx = Arc::clone(&ui);
+/// Tenta la riconnessione finché non riesce, raddoppiando l'attesa a ogni fallimento
+fn reconnect(
+    connection: &Connection,
+    ui: &Mutex<UserInterface>,
+    events: &mpsc::Sender<ProtocolMessage>,
+) -> LineReader {
+    let mut delay = RECONNECT_INITIAL_DELAY;
+    loop {
+        println!("\r⚠️ Connection to {} lost. Reconnecting in {}s...", connection.server_addr, delay.as_secs());
+        thread::sleep(delay);
+
+        let attempt = connect(connection, ui, events).and_then(|(mut stream, mut reader)| {
+            restore_state(connection, &mut stream, &mut reader, ui, events)?;
+            Ok((stream, reader))
+        });
+        match attempt {
+            Ok((stream, reader)) => {
+                *connection.stream.lock().unwrap() = Some(stream);
+                let ui = ui.lock().unwrap();
+                println!("✅ Reconnected to {}", connection.server_addr);
+                print!("{}", ui.show_prompt());
+                io::stdout().flush().unwrap();
+                return reader;
+            }
+            Err(e) => println!("❌ Reconnection failed: {}", e),
+        }
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
+
+struct ChatClient {
+    connection: Arc<Connection>,
+    ui: Arc<Mutex<UserInterface>>,
+}
+
+impl ChatClient {
+    fn new(server_addr: String, tls: Option<TlsSettings>) -> Self {
+        Self {
+            connection: Arc::new(Connection::new(server_addr, tls)),
+            ui: Arc::new(Mutex::new(UserInterface::new())),
+        }
+    }
+
+    /// Dopo il login mostra gli inviti ricevuti mentre si era offline, se ce ne sono
+    fn show_pending_invites(&self) {
+        if let Ok(ProtocolMessage::InviteList { invites }) = self.connection.request(&ProtocolMessage::ListInvites, RESPONSE_TIMEOUT) {
+            if !invites.is_empty() {
+                self.ui.lock().unwrap().handle_response(ProtocolMessage::InviteList { invites });
+            }
+        }
+    }
+
+    /// Dopo il login mostra le menzioni arrivate mentre si era offline, se ce ne sono
+    fn show_unread_mentions(&self) {
+        if !self.ui.lock().unwrap().supports(protocol::capabilities::MENTIONS) {
+            return;
+        }
+        if let Ok(ProtocolMessage::MentionList { mentions }) = self.connection.request(&ProtocolMessage::ListMentions, RESPONSE_TIMEOUT) {
+            if !mentions.is_empty() {
+                self.ui.lock().unwrap().handle_response(ProtocolMessage::MentionList { mentions });
+            }
+        }
+    }
+
+    /// Avvia la sessione interattiva; con le credenziali da riga di comando esegue subito il login
+    fn run(&mut self, credentials: Option<(String, String)>) -> Result<(), Box<dyn std::error::Error>> {
+        let (tx_events, rx_events) = mpsc::channel::<ProtocolMessage>();
+
+        // Connessione al server
+        println!("🔌 Connecting to {}...", self.connection.server_addr);
+        let (stream, reader) = connect(&self.connection, &self.ui, &tx_events)?;
+        *self.connection.stream.lock().unwrap() = Some(stream);
+        println!("✅ Connected to server!");
+        self.ui.lock().unwrap().show_welcome();
+
+        // THREAD 1: Lettura socket (con riconnessione automatica)
+        {
+            let connection = Arc::clone(&self.connection);
+            let ui = Arc::clone(&self.ui);
+            thread::spawn(move || reader_loop(connection, ui, reader, tx_events));
+        }
+
+        // THREAD 2: Stampa gli eventi dal server in tempo reale
+        let ui_for_rx = Arc::clone(&self.ui);
         thread::spawn(move || {
-            for message in rx_raw {
+            for message in rx_events {
                 // Limit lock duration: lock, handle, unlock, then lock, show, unlock.
                 let messages = {
                     let mut ui = ui_for_rx.lock().unwrap();
                     ui.handle_response(message)
                 };
+                let ui = ui_for_rx.lock().unwrap();
                 if let Some(msgs) = messages {
-                    let ui = ui_for_rx.lock().unwrap();
                     ui.show_recent_messages(&msgs);
-                    print!("{}", ui.show_prompt());
-                    io::stdout().flush().unwrap();
                 }
+                // L'evento ha interrotto la riga di input: ripropone il prompt
+                print!("{}", ui.show_prompt());
+                io::stdout().flush().unwrap();
             }
         });
 
+        // THREAD 3: Heartbeat. Se il Pong non arriva in tempo la connessione viene considerata persa
+        {
+            let connection = Arc::clone(&self.connection);
+            thread::spawn(move || loop {
+                thread::sleep(HEARTBEAT_INTERVAL);
+                if connection.is_online() && connection.request(&ProtocolMessage::Ping, PONG_TIMEOUT).is_err() {
+                    connection.drop_connection();
+                }
+            });
+        }
+
+        let ui = &self.ui;
+
+        if let Some((username, password)) = credentials {
+            let login = ProtocolMessage::Login { username: username.clone(), password: password.clone() };
+            match self.connection.request(&login, RESPONSE_TIMEOUT) {
+                Ok(response) => {
+                    let authenticated = matches!(response, ProtocolMessage::AuthResult { success: true, .. });
+                    let mut ui = ui.lock().unwrap();
+                    ui.handle_response(response);
+                    if authenticated {
+                        ui.credentials = Some((username, password));
+                        drop(ui);
+                        self.show_pending_invites();
+                        self.show_unread_mentions();
+                    }
+                }
+                Err(e) => println!("❌ {}", e),
+            }
+            let prompt = ui.lock().unwrap().show_prompt();
+            print!("{}", prompt);
+            io::stdout().flush()?;
+        }
+
         // THREAD PRINCIPALE: Gestione input utente
         loop {
             let mut input = String::new();
@@ -370,31 +1498,69 @@ impl ChatClient {
             };
 
             if let Some(message) = command {
+                // Non inviamo richieste per funzionalità che il server non ha negoziato
+                if let Some(capability) = message.required_capability() {
+                    let supported = ui.lock().unwrap().supports(capability);
+                    if !supported {
+                        println!("❌ This server does not support '{}'", capability);
+                        let prompt = ui.lock().unwrap().show_prompt();
+                        print!("{}", prompt);
+                        io::stdout().flush()?;
+                        continue;
+                    }
+                }
+
                 if matches!(message, ProtocolMessage::Quit) {
-                    self.send_message(&message)?;
+                    let _ = self.connection.send_message(&message);
                     break;
                 }
 
-                let is_join_command = matches!(message, ProtocolMessage::JoinGroup { .. });
                 let is_leave_command = matches!(message, ProtocolMessage::LeaveGroup { .. });
-                let group_name_for_join = if let ProtocolMessage::JoinGroup { group_name } = &message {
-                    Some(group_name.clone())
-                } else {
-                    None
+                let is_logout_command = matches!(message, ProtocolMessage::Logout);
+                // Le credenziali vengono conservate per ripetere il login dopo una riconnessione
+                let credentials = match &message {
+                    ProtocolMessage::Login { username, password } | ProtocolMessage::Register { username, password } => {
+                        Some((username.clone(), password.clone()))
+                    }
+                    _ => None,
                 };
 
-                self.send_message(&message)?;
+                // Attende la risposta a questo comando: lo stato cambia solo dopo la conferma del server
+                match self.connection.request(&message, RESPONSE_TIMEOUT) {
+                    Ok(response) => {
+                        let left_group = is_leave_command && matches!(response, ProtocolMessage::Ok { .. });
+                        let logged_out = is_logout_command && matches!(response, ProtocolMessage::Ok { .. });
+                        let authenticated = matches!(response, ProtocolMessage::AuthResult { success: true, .. });
+                        let messages = {
+                            let mut ui = ui.lock().unwrap();
+                            ui.handle_response(response)
+                        };
 
-                if is_join_command {
-                    if let Some(group_name) = group_name_for_join {
                         let mut ui = ui.lock().unwrap();
-                        ui.state = ClientState::InGroup(group_name);
+                        if authenticated && credentials.is_some() {
+                            ui.credentials = credentials;
+                        }
+                        if left_group {
+                            ui.state = ClientState::Home;
+                            ui.last_seen_message_id = None;
+                            ui.show_available_commands();
+                        }
+                        if logged_out {
+                            ui.state = ClientState::NotAuthenticated;
+                            ui.session_token = None;
+                            ui.credentials = None;
+                            ui.show_available_commands();
+                        }
+                        if let Some(msgs) = messages {
+                            ui.show_recent_messages(&msgs);
+                        }
+                        drop(ui);
+                        if authenticated {
+                            self.show_pending_invites();
+                            self.show_unread_mentions();
+                        }
                     }
-                }
-
-                if is_join_command || is_leave_command {
-                    let ui = ui.lock().unwrap();
-                    ui.show_available_commands();
+                    Err(e) => println!("❌ {}", e),
                 }
             }
 
@@ -410,49 +1576,368 @@ impl ChatClient {
         println!("👋 Goodbye!");
         Ok(())
     }
+}
+
+/// Indirizzo usato se non ne viene indicato uno
+const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:8080";
+
+/// Variabile d'ambiente da cui leggere la password (non è accettata come opzione per non esporla in `ps`)
+const PASSWORD_ENV: &str = "RUGGINE_PASSWORD";
+
+/// Opzioni da riga di comando del client
+#[derive(Debug, Parser)]
+#[command(name = "client", version, about = "Ruggine chat client")]
+struct ClientArgs {
+    /// Indirizzo del server (se assente viene chiesto all'avvio)
+    #[arg(short, long, env = "RUGGINE_SERVER")]
+    server: Option<String>,
+    /// Username per il login automatico (password da RUGGINE_PASSWORD o --password-file)
+    #[arg(short, long, env = "RUGGINE_USERNAME")]
+    username: Option<String>,
+    /// File contenente la password
+    #[arg(long, env = "RUGGINE_PASSWORD_FILE")]
+    password_file: Option<PathBuf>,
+    /// Usa TLS verificando il certificato del server con le CA di questo file PEM
+    #[arg(long, env = "RUGGINE_TLS_CA", conflicts_with = "tls_fingerprint")]
+    tls_ca: Option<PathBuf>,
+    /// Usa TLS accettando solo il certificato con questa impronta SHA-256
+    #[arg(long, env = "RUGGINE_TLS_FINGERPRINT")]
+    tls_fingerprint: Option<String>,
+    /// Nome atteso nel certificato (default: host dell'indirizzo del server)
+    #[arg(long, env = "RUGGINE_TLS_SERVER_NAME")]
+    tls_server_name: Option<String>,
+    #[command(subcommand)]
+    command: Option<OneShotCommand>,
+}
+
+/// Comandi non interattivi: l'output è in JSON lines, i messaggi di stato vanno su stderr
+#[derive(Debug, Subcommand)]
+enum OneShotCommand {
+    /// Invia un messaggio a un gruppo ed esce
+    Send {
+        #[arg(short, long)]
+        group: String,
+        /// Testo del messaggio (se assente viene letto da stdin)
+        message: Option<String>,
+    },
+    /// Stampa gli ultimi messaggi di un gruppo di cui si è membri e poi quelli nuovi, finché si resta nel gruppo
+    Tail {
+        #[arg(short, long)]
+        group: String,
+        /// Numero di messaggi recenti da stampare prima di quelli nuovi
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+    },
+}
+
+impl ClientArgs {
+    /// Legge la password dal file indicato o dalla variabile d'ambiente
+    fn password(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if let Some(path) = &self.password_file {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read password file '{}': {}", path.display(), e))?;
+            return Ok(Some(content.trim_end_matches(['\r', '\n']).to_string()));
+        }
+        Ok(std::env::var(PASSWORD_ENV).ok())
+    }
+
+    /// Parametri TLS, se è stata indicata una CA o un'impronta; altrimenti la connessione è in chiaro
+    fn tls_settings(&self, server_addr: &str) -> Result<Option<TlsSettings>, Box<dyn std::error::Error>> {
+        let trust = match (&self.tls_ca, &self.tls_fingerprint) {
+            (Some(ca), _) => ServerTrust::CaFile(ca.clone()),
+            (None, Some(fingerprint)) => ServerTrust::Fingerprint(fingerprint.clone()),
+            (None, None) => {
+                if self.tls_server_name.is_some() {
+                    return Err("--tls-server-name needs --tls-ca or --tls-fingerprint".into());
+                }
+                return Ok(None);
+            }
+        };
+        let server_name = match &self.tls_server_name {
+            Some(name) => name.clone(),
+            None => server_host(server_addr).to_string(),
+        };
+        Ok(Some(TlsSettings { config: tls::client_config(&trust)?, server_name }))
+    }
+
+    fn credentials(&self) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+        match (&self.username, self.password()?) {
+            (Some(username), Some(password)) => Ok(Some((username.clone(), password))),
+            (Some(_), None) => Err(format!("No password for --username: set {} or use --password-file", PASSWORD_ENV).into()),
+            (None, _) => Ok(None),
+        }
+    }
+}
+
+/// Converte una durata come "90s", "30m", "2h" o "7d" (o un numero di secondi) in secondi;
+/// None se non è valida o non sta in un i64
+fn parse_duration(value: &str) -> Option<i64> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, unit)) if unit.is_ascii_alphabetic() => (&value[..index], unit.to_ascii_lowercase()),
+        _ => (value, 's'),
+    };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    number.parse::<i64>().ok().filter(|n| *n > 0).and_then(|n| n.checked_mul(multiplier))
+}
+
+/// Host di un indirizzo "host:porta" (anche "[::1]:8080")
+fn server_host(server_addr: &str) -> &str {
+    let host = server_addr.rsplit_once(':').map_or(server_addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.parse::<std::net::IpAddr>().map_or(host == "localhost", |ip| ip.is_loopback())
+}
+
+/// Una riga di output JSON della modalità non interattiva
+#[derive(Serialize)]
+struct MessageLine<'a> {
+    group: &'a str,
+    #[serde(flatten)]
+    message: &'a ChatMessage,
+}
+
+fn print_json_line(group: &str, message: &ChatMessage) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", serde_json::to_string(&MessageLine { group, message })?)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Connessione sincrona della modalità non interattiva: nessun thread e nessuna riconnessione
+struct ScriptSession {
+    stream: Link,
+    reader: LineReader,
+    next_request_id: u64,
+    // Eventi ricevuti mentre si attende una risposta: tail li elabora dopo aver stampato la cronologia
+    events: mpsc::Sender<ProtocolMessage>,
+    pending_events: mpsc::Receiver<ProtocolMessage>,
+}
+
+impl ScriptSession {
+    /// Si connette, esegue l'handshake e il login
+    fn open(server_addr: &str, tls: Option<&TlsSettings>, username: String, password: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let (stream, reader) = open_link(server_addr, tls)?;
+        let (events, pending_events) = mpsc::channel();
+        let mut session = Self { stream, reader, next_request_id: 1, events, pending_events };
+
+        let request_id = session.next_request_id();
+        handshake(&mut session.stream, &mut session.reader, request_id, &session.events)?;
+        match session.request(ProtocolMessage::Login { username, password })? {
+            ProtocolMessage::AuthResult { success: true, .. } => Ok(session),
+            ProtocolMessage::AuthResult { message, .. } => Err(message.into()),
+            other => Err(format!("Unexpected response to Login: {:?}", other).into()),
+        }
+    }
 
-    fn send_message(&mut self, message: &ProtocolMessage) -> Result<(), Box<dyn std::error::Error>> {
-        let data = message.to_wire_format()?;
-        self.stream.write_all(data.as_bytes())?;
-        self.stream.flush()?;
+    fn next_request_id(&mut self) -> String {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        id.to_string()
+    }
+
+    fn request(&mut self, message: ProtocolMessage) -> Result<ProtocolMessage, Box<dyn std::error::Error>> {
+        let request_id = self.next_request_id();
+        match exchange(&mut self.stream, &mut self.reader, request_id, message, &self.events)? {
+            ProtocolMessage::Error { message } => Err(message.into()),
+            response => Ok(response),
+        }
+    }
+
+    fn send(&mut self, group: String, content: String) -> Result<(), Box<dyn std::error::Error>> {
+        match self.request(ProtocolMessage::SendMessage { group_name: group.clone(), content, reply_to: None })? {
+            ProtocolMessage::NewMessage { message, .. } => print_json_line(&group, &message),
+            other => Err(format!("Unexpected response to SendMessage: {:?}", other).into()),
+        }
+    }
+
+    /// Stampa la cronologia e i nuovi messaggi del gruppo. Serve essere già membri: JoinGroup
+    /// renderebbe membro l'utente di un gruppo pubblico, quindi prima si controllano i suoi gruppi.
+    /// Termina con errore se l'utente viene rimosso o se il gruppo viene archiviato o eliminato.
+    fn tail(&mut self, mut group: String, lines: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if !group.starts_with('@') {
+            match self.request(ProtocolMessage::ListGroups)? {
+                ProtocolMessage::GroupListResponse { groups } if groups.iter().any(|g| g.name == group) => {}
+                ProtocolMessage::GroupListResponse { .. } => {
+                    return Err(format!("You are not a member of group '{}'", group).into());
+                }
+                other => return Err(format!("Unexpected response to ListGroups: {:?}", other).into()),
+            }
+        }
+        match self.request(ProtocolMessage::JoinGroup { group_name: group.clone() })? {
+            ProtocolMessage::GroupJoined { .. } => {}
+            other => return Err(format!("Unexpected response to JoinGroup: {:?}", other).into()),
+        }
+
+        // Il server limita la dimensione di ogni pagina di cronologia: si risale pagina per pagina
+        let mut backlog: Vec<ChatMessage> = Vec::new();
+        let mut before = None;
+        while backlog.len() < lines {
+            let fetch = ProtocolMessage::FetchHistory {
+                group_name: group.clone(),
+                before: before.clone(),
+                limit: u32::try_from(lines - backlog.len()).unwrap_or(u32::MAX),
+                after: None,
+            };
+            match self.request(fetch)? {
+                ProtocolMessage::HistoryPage { mut messages, has_more } => {
+                    before = messages.first().map(|message| message.id.clone());
+                    let complete = !has_more || messages.is_empty();
+                    messages.append(&mut backlog);
+                    backlog = messages;
+                    if complete {
+                        break;
+                    }
+                }
+                other => return Err(format!("Unexpected response to FetchHistory: {:?}", other).into()),
+            }
+        }
+        for message in &backlog[backlog.len().saturating_sub(lines)..] {
+            print_json_line(&group, message)?;
+        }
+
+        // I messaggi arrivati durante il caricamento della cronologia possono essere già stati stampati
+        let pending: Vec<ProtocolMessage> = self.pending_events.try_iter().collect();
+        for event in pending {
+            if let ProtocolMessage::NewMessage { message, .. } = &event {
+                if backlog.iter().any(|printed| printed.id == message.id) {
+                    continue;
+                }
+            }
+            self.tail_event(&mut group, event)?;
+        }
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err("Server closed the connection".into());
+            }
+            let event = Envelope::from_wire_format(&line)?.message;
+            self.tail_event(&mut group, event)?;
+        }
+    }
+
+    /// Gestisce un evento ricevuto durante tail; restituisce un errore se non si può più seguire il gruppo
+    fn tail_event(&mut self, group: &mut String, event: ProtocolMessage) -> Result<(), Box<dyn std::error::Error>> {
+        match event {
+            ProtocolMessage::NewMessage { group: message_group, message } if message_group == *group => {
+                print_json_line(group, &message)?;
+            }
+            ProtocolMessage::GroupUpdated { previous_name, group: updated } if previous_name == *group => {
+                *group = updated.name;
+            }
+            ProtocolMessage::RemovedFromGroup { group_name, removed_by, .. } if group_name == *group => {
+                return Err(format!("Removed from group '{}' by {}", group_name, removed_by).into());
+            }
+            ProtocolMessage::GroupArchived { group_name, archived_by } if group_name == *group => {
+                return Err(format!("Group '{}' was archived by {}", group_name, archived_by).into());
+            }
+            ProtocolMessage::GroupDeleted { group_name, deleted_by } if group_name == *group => {
+                return Err(format!("Group '{}' was deleted by {}", group_name, deleted_by).into());
+            }
+            ProtocolMessage::Ping => {
+                let data = Envelope::event(ProtocolMessage::Pong).to_wire_format()?;
+                self.stream.write_all(data.as_bytes())?;
+                self.stream.flush()?;
+            }
+            _ => {}
+        }
         Ok(())
     }
+}
 
-    /*fn receive_message(&mut self) -> Result<ProtocolMessage, Box<dyn std::error::Error>> {
-        let mut reader = BufReader::new(&self.stream);
-        let mut line = String::new();
-        reader.read_line(&mut line)?;
-        
-        let message = ProtocolMessage::from_wire_format(&line)?;
-        Ok(message)
-    }*/
+/// Esegue un comando non interattivo
+fn run_one_shot(
+    server_addr: &str,
+    tls: Option<TlsSettings>,
+    credentials: Option<(String, String)>,
+    command: OneShotCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, password) = credentials.ok_or("Non-interactive commands need --username and a password")?;
+    let mut session = ScriptSession::open(server_addr, tls.as_ref(), username, password)?;
+    match command {
+        OneShotCommand::Send { group, message } => {
+            let content = match message {
+                Some(message) => message,
+                None => {
+                    let mut content = String::new();
+                    io::Read::read_to_string(&mut io::stdin(), &mut content)?;
+                    content.trim_end().to_string()
+                }
+            };
+            if content.trim().is_empty() {
+                return Err("Refusing to send an empty message".into());
+            }
+            session.send(group, content)
+        }
+        OneShotCommand::Tail { group, lines } => session.tail(group, lines),
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = ClientArgs::parse();
+    let credentials = match args.credentials() {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Modalità non interattiva: niente banner né prompt, solo JSON su stdout
+    if let Some(command) = args.command.take() {
+        let server_addr = args.server.as_deref().unwrap_or(DEFAULT_SERVER_ADDR);
+        let result = args.tls_settings(server_addr)
+            .and_then(|tls| run_one_shot(server_addr, tls, credentials, command));
+        if let Err(e) = result {
+            eprintln!("❌ {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     println!("🦀 Ruggine Chat Client");
     println!("====================");
     
-    // Richiede l'indirizzo del server
-    print!("Enter server address (default: 127.0.0.1:8080): ");
-    io::stdout().flush()?;
-    
-    let mut server_addr = String::new();
-    io::stdin().read_line(&mut server_addr)?;
-    let server_addr = server_addr.trim();
-    let server_addr = if server_addr.is_empty() {
-        "127.0.0.1:8080"
-    } else {
-        server_addr
+    // Richiede l'indirizzo del server, se non è stato indicato da riga di comando
+    let server_addr = match args.server.clone() {
+        Some(server_addr) => server_addr,
+        None => {
+            print!("Enter server address (default: {}): ", DEFAULT_SERVER_ADDR);
+            io::stdout().flush()?;
+
+            let mut server_addr = String::new();
+            io::stdin().read_line(&mut server_addr)?;
+            let server_addr = server_addr.trim();
+            if server_addr.is_empty() {
+                DEFAULT_SERVER_ADDR.to_string()
+            } else {
+                server_addr.to_string()
+            }
+        }
     };
     
-    // Connessione al server
-    println!("🔌 Connecting to {}...", server_addr);
-    let stream = TcpStream::connect(server_addr)?;
-    println!("✅ Connected to server!");
-    
+    let tls = match args.tls_settings(&server_addr) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(2);
+        }
+    };
+    if tls.is_none() && !is_loopback(server_host(&server_addr)) {
+        println!("⚠️ TLS disabled: use --tls-ca or --tls-fingerprint to encrypt the connection");
+    }
+
     // Crea e avvia il client
-    let mut client = ChatClient::new(stream)?;
-    client.run()?;
+    let mut client = ChatClient::new(server_addr, tls);
+    client.run(credentials)?;
 
     Ok(())
 }
@@ -1,70 +1,239 @@
-use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
-use std::io::{BufRead, BufReader, Write};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use std::collections::HashMap;
 
-use ruggine::common::Message;
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Notify;
+use tokio_rustls::TlsAcceptor;
+
+use ruggine::common::{ChatMessage, Group, GroupRole, GroupVisibility};
+use ruggine::config::{QueueFullPolicy, ServerConfig};
 use ruggine::database::Database;
-use ruggine::protocol::ProtocolMessage;
+use ruggine::protocol::{self, capabilities, Envelope, ProtocolMessage, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+use ruggine::tls;
+
+/// Utenti connessi: user_id -> (coda in uscita con le funzionalità negoziate, group_id corrente)
+type ConnectedUsers = Arc<Mutex<HashMap<String, (Outbound, Option<String>)>>>;
+
+/// Coda in uscita di una connessione.
+/// I messaggi accodati vengono scritti sul socket da un task dedicato, così un client
+/// lento non blocca chi invia messaggi in broadcast.
+#[derive(Clone)]
+struct Outbound {
+    sender: mpsc::Sender<Envelope>,
+    closed: Arc<Notify>,
+    full_policy: QueueFullPolicy,
+    /// Funzionalità negoziate dalla connessione: gli eventi che ne richiedono altre non vengono inviati
+    capabilities: Arc<Vec<String>>,
+}
+
+impl Outbound {
+    /// Crea la coda e avvia il task di scrittura sulla metà in scrittura del socket
+    fn spawn<W>(mut writer: W, capacity: usize, full_policy: QueueFullPolicy) -> Self
+    where
+        W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Envelope>(capacity);
+        let closed = Arc::new(Notify::new());
+        let closed_for_writer = Arc::clone(&closed);
+
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let response_data = match message.to_wire_format() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        eprintln!("❌ Error serializing message: {}", e);
+                        continue;
+                    }
+                };
+                let written = async {
+                    writer.write_all(response_data.as_bytes()).await?;
+                    writer.flush().await
+                };
+                if let Err(e) = written.await {
+                    eprintln!("❌ Error writing to client: {}", e);
+                    closed_for_writer.notify_one();
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+
+        Self {
+            sender,
+            closed,
+            full_policy,
+            capabilities: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Copia della coda da registrare in ConnectedUsers, con le funzionalità negoziate nell'handshake
+    fn with_capabilities(&self, capabilities: &Option<Vec<String>>) -> Self {
+        Self {
+            capabilities: Arc::new(capabilities.clone().unwrap_or_default()),
+            ..self.clone()
+        }
+    }
+
+    /// Il client ha negoziato la funzionalità richiesta dall'evento (se ne richiede una)?
+    fn accepts(&self, message: &ProtocolMessage) -> bool {
+        message.required_capability().is_none_or(|capability| self.capabilities.iter().any(|c| c == capability))
+    }
+
+    /// Indica se le due code appartengono alla stessa connessione
+    fn is_same_connection(&self, other: &Outbound) -> bool {
+        Arc::ptr_eq(&self.closed, &other.closed)
+    }
+
+    /// Accoda una risposta diretta, attendendo se la coda è piena
+    async fn send(&self, response: Envelope) -> bool {
+        self.sender.send(response).await.is_ok()
+    }
+
+    /// Accoda un evento senza bloccare, applicando la policy se la coda è piena.
+    /// Gli eventi di funzionalità non negoziate vengono scartati senza errori.
+    /// Restituisce false se il messaggio non è stato accodato per coda piena o chiusa.
+    fn try_send(&self, message: ProtocolMessage) -> bool {
+        if !self.accepts(&message) {
+            return true;
+        }
+        match self.sender.try_send(Envelope::event(message)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if self.full_policy == QueueFullPolicy::Disconnect {
+                    // Il task di lettura vedrà la notifica e farà il cleanup
+                    self.closed.notify_one();
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Stato di una singola connessione client
+#[derive(Default)]
+struct ClientSession {
+    user_id: Option<String>,
+    /// Token della sessione persistente associata alla connessione
+    token: Option<String>,
+    /// Funzionalità negoziate con Hello/Welcome (None finché l'handshake non è completato)
+    capabilities: Option<Vec<String>>,
+}
+
+impl ClientSession {
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.as_ref().is_some_and(|caps| caps.iter().any(|c| c == capability))
+    }
+}
+
+/// Funzione helper per leggere usage CPU (user + system) in millisecondi
+fn read_cpu_time_ms() -> u128 {
+    unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) == 0 {
+            let user_sec = usage.ru_utime.tv_sec as u128;
+            let user_usec = usage.ru_utime.tv_usec as u128;
+            let sys_sec = usage.ru_stime.tv_sec as u128;
+            let sys_usec = usage.ru_stime.tv_usec as u128;
+            (user_sec * 1000 + user_usec / 1000) + (sys_sec * 1000 + sys_usec / 1000)
+        } else {
+            0
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = match ServerConfig::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("❌ Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("🦀 Ruggine Chat Server");
     println!("======================");
-    
-    let database = Database::new("ruggine.db")?;
+
+    let database = Database::new(&config.database_path)?;
     let database = Arc::new(database);
     
-    let connected_users: Arc<Mutex<HashMap<String, (TcpStream, Option<String>)>>> = Arc::new(Mutex::new(HashMap::new()));
-    
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-    println!("✅ Server listening on 127.0.0.1:8080");
+    let connected_users: ConnectedUsers = Arc::new(Mutex::new(HashMap::new()));
     
-    // Thread per il logging delle performance (ogni 2 minuti, con tempo CPU)
-    let db_for_stats = Arc::clone(&database);
-    thread::spawn(move || {
-        // Funzione helper locale per leggere usage CPU (user + system) in millisecondi
-        fn read_cpu_time_ms() -> u128 {
-            unsafe {
-                let mut usage: libc::rusage = std::mem::zeroed();
-                if libc::getrusage(libc::RUSAGE_SELF, &mut usage) == 0 {
-                    let user_sec = usage.ru_utime.tv_sec as u128;
-                    let user_usec = usage.ru_utime.tv_usec as u128;
-                    let sys_sec = usage.ru_stime.tv_sec as u128;
-                    let sys_usec = usage.ru_stime.tv_usec as u128;
-                    (user_sec * 1000 + user_usec / 1000) + (sys_sec * 1000 + sys_usec / 1000)
-                } else {
-                    0
-                }
+    // TLS è attivo solo se sono stati configurati certificato e chiave
+    let tls_acceptor = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let (tls_config, fingerprint) = tls::server_config(cert, key)?;
+            println!("🔒 TLS enabled, certificate SHA-256 fingerprint: {}", fingerprint);
+            Some(TlsAcceptor::from(tls_config))
+        }
+        _ => {
+            let local_only = config.bind_address.parse::<std::net::SocketAddr>().is_ok_and(|addr| addr.ip().is_loopback());
+            if !local_only {
+                println!("⚠️ TLS disabled: traffic (including passwords) is sent in plain text");
             }
+            None
         }
+    };
 
+    let listener = TcpListener::bind(&config.bind_address).await?;
+    println!("✅ Server listening on {}", config.bind_address);
+    println!("🗄️ Database: {}, performance log: {}", config.database_path.display(), config.performance_log.display());
+    println!("📮 Outbound queue: {} messages, policy when full: {:?}", config.outbound_queue, config.queue_full_policy);
+    println!("💓 Heartbeat every {}s, disconnect after {} missed", config.heartbeat_secs, config.heartbeat_max_missed);
+    
+    // Task per il logging delle performance (ogni stats_interval_secs, con tempo CPU)
+    let db_for_stats = Arc::clone(&database);
+    let users_for_stats = Arc::clone(&connected_users);
+    let config_for_stats = Arc::clone(&config);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config_for_stats.stats_interval());
+        interval.tick().await; // il primo tick è immediato
         let mut last_wall = Instant::now();
         let mut last_cpu_ms = read_cpu_time_ms();
-        let mut last_log = Instant::now();
         loop {
-            thread::sleep(Duration::from_secs(120)); // intervallo fisso
-            if last_log.elapsed() >= Duration::from_secs(120) {
-                let now_cpu_ms = read_cpu_time_ms();
-                let delta_cpu_ms = now_cpu_ms.saturating_sub(last_cpu_ms);
-                let wall_elapsed_ms = last_wall.elapsed().as_millis();
-                log_performance_stats(&db_for_stats, now_cpu_ms, delta_cpu_ms, wall_elapsed_ms as u128);
-                last_cpu_ms = now_cpu_ms;
-                last_wall = Instant::now();
-                last_log = Instant::now();
-            }
+            interval.tick().await;
+            let now_cpu_ms = read_cpu_time_ms();
+            let delta_cpu_ms = now_cpu_ms.saturating_sub(last_cpu_ms);
+            let wall_elapsed_ms = last_wall.elapsed().as_millis();
+            let db = Arc::clone(&db_for_stats);
+            let log_path = config_for_stats.performance_log.clone();
+            let online = users_for_stats.lock().unwrap().len();
+            // Le query SQLite e la scrittura su file sono bloccanti
+            let _ = tokio::task::spawn_blocking(move || {
+                log_performance_stats(&db, &log_path, online, now_cpu_ms, delta_cpu_ms, wall_elapsed_ms);
+            }).await;
+            last_cpu_ms = now_cpu_ms;
+            last_wall = Instant::now();
         }
     });
     
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
                 let database_clone = Arc::clone(&database);
                 let connected_users_clone = Arc::clone(&connected_users);
+                let config_clone = Arc::clone(&config);
+                
+                let tls_acceptor = tls_acceptor.clone();
                 
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, database_clone, connected_users_clone) {
+                tokio::spawn(async move {
+                    // L'handshake TLS avviene nel task della connessione, senza rallentare l'accept
+                    let result = match tls_acceptor {
+                        Some(acceptor) => match acceptor.accept(stream).await {
+                            Ok(tls_stream) => handle_client(tls_stream, database_clone, connected_users_clone, config_clone).await,
+                            Err(e) => {
+                                eprintln!("❌ TLS handshake with {} failed: {}", addr, e);
+                                return;
+                            }
+                        },
+                        None => handle_client(stream, database_clone, connected_users_clone, config_clone).await,
+                    };
+                    if let Err(e) = result {
                         eprintln!("❌ Error handling client: {}", e);
                     }
                 });
@@ -74,23 +243,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
     }
-    
-    Ok(())
 }
 
-fn log_performance_stats(database: &Database, cumulative_cpu_ms: u128, delta_cpu_ms: u128, wall_elapsed_ms: u128) {
+fn log_performance_stats(database: &Database, log_path: &std::path::Path, online_users: usize, cumulative_cpu_ms: u128, delta_cpu_ms: u128, wall_elapsed_ms: u128) {
     match (database.get_user_count(), database.get_group_count(), database.get_message_count()) {
         (Ok(users), Ok(groups), Ok(messages)) => {
             let timestamp = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S");
             let stats_line = format!(
-                "{} | users={} groups={} messages={} cpu_total_ms={} cpu_delta_ms={} wall_interval_ms={}",
-                timestamp, users, groups, messages, cumulative_cpu_ms, delta_cpu_ms, wall_elapsed_ms
+                "{} | users={} online={} groups={} messages={} cpu_total_ms={} cpu_delta_ms={} wall_interval_ms={}",
+                timestamp, users, online_users, groups, messages, cumulative_cpu_ms, delta_cpu_ms, wall_elapsed_ms
             );
             println!("📊 {}", stats_line);
 
             use std::fs::OpenOptions;
             use std::io::Write;
-            if let Ok(mut f) = OpenOptions::new().create(true).append(true).open("server_performance.log") {
+            if let Ok(mut f) = OpenOptions::new().create(true).append(true).open(log_path) {
                 if let Err(e) = writeln!(f, "{}", stats_line) {
                     eprintln!("❌ Failed to append performance log: {}", e);
                 }
@@ -102,35 +269,97 @@ fn log_performance_stats(database: &Database, cumulative_cpu_ms: u128, delta_cpu
     }
 }
 
-fn handle_client(
-    mut stream: TcpStream,
+async fn handle_client<S>(
+    stream: S,
     database: Arc<Database>,
-    connected_users: Arc<Mutex<HashMap<String, (TcpStream, Option<String>)>>>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut current_user_id: Option<String> = None;
+    connected_users: ConnectedUsers,
+    config: Arc<ServerConfig>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut session = ClientSession::default();
+    let (read_half, write_half) = tokio::io::split(stream);
+    let outbound = Outbound::spawn(write_half, config.outbound_queue, config.queue_full_policy);
+    let mut lines = BufReader::new(read_half).lines();
+
+    // Heartbeat: una connessione inattiva viene chiusa dopo heartbeat_max_missed intervalli senza righe ricevute;
+    // ai client che hanno negoziato HEARTBEAT inviamo anche un Ping per ogni intervallo mancato
+    let mut heartbeat = tokio::time::interval(config.heartbeat_interval());
+    heartbeat.tick().await; // il primo tick è immediato
+    let connected_at = Instant::now();
+    let mut last_activity = connected_at;
+    let mut missed_beats = 0;
     
     loop {
-        let mut line = String::new();
-        let mut reader = BufReader::new(&stream);
-        
-        match reader.read_line(&mut line) {
-            Ok(0) => break, // Client disconnesso
-            Ok(_) => {
-                if let Ok(message) = ProtocolMessage::from_wire_format(&line) {
-                    let response = process_message(message, &database, &connected_users, &mut current_user_id, &stream);
+        // next_line è cancel-safe: il tick del heartbeat non fa perdere righe lette a metà
+        let read = tokio::select! {
+            read = lines.next_line() => read,
+            _ = outbound.closed.notified() => {
+                eprintln!("❌ Closing slow or unreachable client");
+                break;
+            }
+            _ = heartbeat.tick() => {
+                // Chi non completa l'handshake entro un intervallo non occupa la connessione oltre
+                if session.capabilities.is_none() && connected_at.elapsed() >= config.heartbeat_interval() {
+                    println!("💤 Client did not send Hello within {}s, closing connection", config.heartbeat_secs);
+                    break;
+                }
+                if last_activity.elapsed() < config.heartbeat_interval() {
+                    continue;
+                }
+                if missed_beats >= config.heartbeat_max_missed {
+                    println!("💤 Connection of {} idle for {} heartbeats, closing it", session.user_id.as_deref().unwrap_or("anonymous client"), missed_beats);
+                    break;
+                }
+                missed_beats += 1;
+                if session.supports(capabilities::HEARTBEAT) {
+                    outbound.try_send(ProtocolMessage::Ping);
+                }
+                continue;
+            }
+        };
+
+        match read {
+            Ok(None) => break, // Client disconnesso
+            Ok(Some(line)) => {
+                last_activity = Instant::now();
+                missed_beats = 0;
+
+                if let Ok(Envelope { request_id, message }) = Envelope::from_wire_format(&line) {
+                    // Risposta al nostro Ping: basta aver aggiornato last_activity
+                    if matches!(message, ProtocolMessage::Pong) {
+                        continue;
+                    }
+
+                    let is_hello = matches!(message, ProtocolMessage::Hello { .. });
+
+                    // process_message usa rusqlite (bloccante): lo eseguiamo sul pool di spawn_blocking
+                    let database = Arc::clone(&database);
+                    let connected_users_for_task = Arc::clone(&connected_users);
+                    let outbound_for_task = outbound.clone();
+                    let config_for_task = Arc::clone(&config);
+                    let mut task_session = std::mem::take(&mut session);
+                    let (response, task_session) = tokio::task::spawn_blocking(move || {
+                        let response = process_message(message, &database, &connected_users_for_task, &mut task_session, &outbound_for_task, &config_for_task);
+                        (response, task_session)
+                    }).await?;
+                    session = task_session;
                     
-                    if let Ok(response_data) = response.to_wire_format() {
-                        if let Err(e) = stream.write_all(response_data.as_bytes()) {
-                            eprintln!("❌ Error writing to client: {}", e);
-                            break;
-                        }
-                        if let Err(e) = stream.flush() {
-                            eprintln!("❌ Error flushing to client: {}", e);
-                            break;
-                        }
+                    // La risposta riporta il request_id della richiesta
+                    if !outbound.send(Envelope::new(request_id, response)).await {
+                        eprintln!("❌ Error writing to client: connection closed");
+                        break;
+                    }
+
+                    // Handshake rifiutato (versione incompatibile): chiudiamo dopo aver inviato l'errore
+                    if is_hello && session.capabilities.is_none() {
+                        break;
                     }
                 }
             }
+            // Con TLS un client che chiude il socket senza close_notify è una normale disconnessione
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
             Err(e) => {
                 eprintln!("❌ Error reading from client: {}", e);
                 break;
@@ -138,17 +367,286 @@ fn handle_client(
         }
     }
     
-    // Cleanup quando il client si disconnette
-    if let Some(user_id) = current_user_id {
-        connected_users.lock().unwrap().remove(&user_id);
-        println!("🔌 User {} disconnected", user_id);
+    // Cleanup quando il client si disconnette.
+    // L'utente potrebbe essersi già riconnesso su un'altra connessione: rimuoviamo solo la nostra voce.
+    if let Some(user_id) = session.user_id {
+        let mut users = connected_users.lock().unwrap();
+        if users.get(&user_id).is_some_and(|(user_outbound, _)| user_outbound.is_same_connection(&outbound)) {
+            users.remove(&user_id);
+            println!("🔌 User {} disconnected", user_id);
+        }
         //debug_print_connected_users(&connected_users);
     }
     
     Ok(())
 }
 
-fn debug_print_connected_users(connected_users: &Arc<Mutex<HashMap<String, (TcpStream, Option<String>)>>>) {
+/// Crea una sessione persistente per l'utente appena autenticato
+fn issue_session_token(database: &Database, user_id: &str, ttl_secs: i64) -> Option<String> {
+    match database.create_session(user_id, ttl_secs) {
+        Ok(token) => Some(token),
+        Err(e) => {
+            eprintln!("❌ Failed to create session for {}: {}", user_id, e);
+            None
+        }
+    }
+}
+
+/// Memorizza il gruppo corrente nella sessione persistente, per poterlo ripristinare con Resume
+fn remember_session_group(database: &Database, session_token: &Option<String>, group_id: Option<&str>) {
+    if let Some(token) = session_token {
+        if let Err(e) = database.update_session_group(token, group_id) {
+            eprintln!("❌ Failed to update session: {}", e);
+        }
+    }
+}
+
+/// Aggiorna l'ultimo messaggio letto dall'utente nel gruppo in cui si trova
+fn mark_group_read(database: &Database, user_id: &str, group_id: &str) {
+    if let Err(e) = database.mark_group_read(user_id, group_id) {
+        eprintln!("❌ Failed to update last read message: {}", e);
+    }
+}
+
+/// Accoda un messaggio a tutti gli utenti connessi che si trovano nel gruppo indicato.
+/// La scrittura sul socket avviene nel task di ogni connessione.
+fn broadcast_to_group(
+    connected_users: &ConnectedUsers,
+    group_id: &str,
+    except_user_id: Option<&str>,
+    message: ProtocolMessage,
+) {
+    for (connected_user_id, (user_outbound, current_group)) in connected_users.lock().unwrap().iter() {
+        if current_group.as_deref() != Some(group_id) || Some(connected_user_id.as_str()) == except_user_id {
+            continue;
+        }
+        if !user_outbound.try_send(message.clone()) {
+            eprintln!("❌ Outbound queue full or closed for {} ({:?})", connected_user_id, user_outbound.full_policy);
+        }
+    }
+}
+
+/// Nome con cui il database conosce il gruppo: "@utente" indica la conversazione diretta con quell'utente
+fn stored_group_name(database: &Database, user_id: &str, group_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    match group_name.strip_prefix('@') {
+        Some(username) => Ok(database.get_direct_conversation(user_id, username)?.group_name),
+        None => Ok(group_name.to_string()),
+    }
+}
+
+/// Apre la conversazione diretta con `username` e ci sposta l'utente, come JoinGroup per i gruppi
+fn enter_direct_conversation(
+    database: &Database,
+    connected_users: &ConnectedUsers,
+    session_token: &Option<String>,
+    user_id: &str,
+    username: &str,
+    config: &ServerConfig,
+) -> ProtocolMessage {
+    match database.open_direct_conversation(user_id, username) {
+        Ok(conversation) => {
+            if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
+                *current_group = Some(conversation.group_id.clone());
+            }
+            remember_session_group(database, session_token, Some(&conversation.group_id));
+            mark_group_read(database, user_id, &conversation.group_id);
+            println!("💬 User {} opened the direct conversation with {}", user_id, username);
+
+            let recent_messages = database.get_recent_messages(&conversation.group_name, config.recent_messages)
+                .unwrap_or_else(|_| Vec::new());
+            let group = Group {
+                id: conversation.group_id,
+                name: format!("@{}", conversation.partner_username),
+                members: vec![user_id.to_string(), conversation.partner_id],
+                creator_id: "".to_string(),
+                created_at: "".to_string(),
+                visibility: GroupVisibility::Private,
+                description: None,
+                topic: None,
+                archived: false,
+                unread_count: 0,
+            };
+            ProtocolMessage::GroupJoined { group, recent_messages }
+        }
+        Err(e) => ProtocolMessage::Error {
+            message: format!("Failed to open direct conversation: {}", e),
+        },
+    }
+}
+
+/// Avvisa chi si trova nel gruppo che nome, argomento o descrizione sono cambiati
+/// e restituisce lo stesso evento come risposta per chi ha fatto la modifica
+fn group_updated(connected_users: &ConnectedUsers, user_id: &str, previous_name: String, group: Group) -> ProtocolMessage {
+    let group_id = group.id.clone();
+    let update = ProtocolMessage::GroupUpdated { previous_name, group };
+    broadcast_to_group(connected_users, &group_id, Some(user_id), update.clone());
+    update
+}
+
+/// Invia a chi si trova nel gruppo l'evento su un messaggio modificato e lo restituisce come risposta
+/// per chi ha fatto la modifica. L'evento riceve il nome del gruppo visto dal destinatario:
+/// nelle conversazioni dirette ognuno lo vede come "@altro_utente".
+fn broadcast_message_event(
+    database: &Database,
+    connected_users: &ConnectedUsers,
+    group: &Group,
+    user_id: &str,
+    event: impl Fn(String) -> ProtocolMessage,
+) -> ProtocolMessage {
+    // Il nome visto da ogni destinatario richiede il database: lo calcoliamo dopo aver rilasciato il lock
+    let recipients: Vec<(String, Outbound)> = connected_users.lock().unwrap()
+        .iter()
+        .filter(|(connected_user_id, (_, current_group))| {
+            current_group.as_deref() == Some(group.id.as_str()) && connected_user_id.as_str() != user_id
+        })
+        .map(|(connected_user_id, (user_outbound, _))| (connected_user_id.clone(), user_outbound.clone()))
+        .collect();
+    for (connected_user_id, user_outbound) in recipients {
+        user_outbound.try_send(event(database.conversation_name(&group.name, &connected_user_id)));
+    }
+    event(database.conversation_name(&group.name, user_id))
+}
+
+/// Aggiunge o toglie una reazione e invia i nuovi conteggi a chi si trova nel gruppo
+fn update_reaction(
+    database: &Database,
+    connected_users: &ConnectedUsers,
+    user_id: &str,
+    message_id: &str,
+    emoji: &str,
+    add: bool,
+) -> ProtocolMessage {
+    match database.set_reaction(message_id, user_id, emoji, add) {
+        Ok((group, message)) => {
+            println!("😀 User {} {} {} on message {}", user_id, if add { "added" } else { "removed" }, emoji, message.id);
+            broadcast_message_event(database, connected_users, &group, user_id, |group| ProtocolMessage::ReactionsUpdated {
+                group,
+                message_id: message.id.clone(),
+                reactions: message.reactions.clone(),
+            })
+        }
+        Err(e) => ProtocolMessage::Error {
+            message: format!("Failed to update reaction: {}", e),
+        },
+    }
+}
+
+/// Avvisa i membri online che il gruppo è stato archiviato o eliminato; chi si trovava nel gruppo
+/// torna nella home. Restituisce lo stesso evento come risposta per chi ha eseguito l'operazione.
+fn notify_group_closed(
+    connected_users: &ConnectedUsers,
+    group_id: &str,
+    member_ids: &[String],
+    user_id: &str,
+    event: ProtocolMessage,
+) -> ProtocolMessage {
+    let mut users_map = connected_users.lock().unwrap();
+    for member_id in member_ids {
+        if let Some((member_outbound, current_group)) = users_map.get_mut(member_id) {
+            if current_group.as_deref() == Some(group_id) {
+                *current_group = None;
+            }
+            if member_id != user_id {
+                member_outbound.try_send(event.clone());
+            }
+        }
+    }
+    event
+}
+
+/// Avvisa l'utente (se online) che è stato rimosso da un gruppo; se si trovava nel gruppo torna nella home
+fn notify_removed_from_group(
+    database: &Database,
+    connected_users: &ConnectedUsers,
+    user_id: &str,
+    group_name: &str,
+    removed_by_id: &str,
+    banned: bool,
+    banned_until: Option<DateTime<Utc>>,
+) {
+    let group_id = database.get_group_id(group_name).ok();
+    let removed_by = database.get_username(removed_by_id).unwrap_or_default();
+    if let Some((user_outbound, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
+        if group_id.is_some() && *current_group == group_id {
+            *current_group = None;
+        }
+        user_outbound.try_send(ProtocolMessage::RemovedFromGroup {
+            group_name: group_name.to_string(),
+            removed_by,
+            banned,
+            banned_until,
+        });
+    }
+}
+
+/// Username citati con @username nel testo, senza duplicati e senza la punteggiatura finale
+fn mentioned_usernames(content: &str) -> Vec<String> {
+    let mut usernames: Vec<String> = Vec::new();
+    for token in content.split_whitespace() {
+        if let Some(username) = token.strip_prefix('@') {
+            let username = username.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_');
+            if !username.is_empty() && !usernames.iter().any(|u| u == username) {
+                usernames.push(username.to_string());
+            }
+        }
+    }
+    usernames
+}
+
+/// Registra le menzioni valide (membri del gruppo diversi dall'autore) e le notifica con Mentioned.
+/// Chi si trova già nel gruppo vede il messaggio con NewMessage, quindi la menzione vale come letta.
+fn notify_mentions(
+    database: &Database,
+    connected_users: &ConnectedUsers,
+    group_name: &str,
+    group_id: &str,
+    user_id: &str,
+    message: &ChatMessage,
+) {
+    let members = database.get_group_members(group_name).unwrap_or_default();
+    let usernames: Vec<String> = mentioned_usernames(&message.content)
+        .into_iter()
+        .filter(|username| *username != message.username && members.contains(username))
+        .collect();
+    if usernames.is_empty() {
+        return;
+    }
+
+    let mentioned_ids = match database.save_mentions(&message.id, &usernames) {
+        Ok(ids) => ids,
+        Err(e) => {
+            eprintln!("❌ Failed to save mentions of message {}: {}", message.id, e);
+            return;
+        }
+    };
+    println!("🔔 User {} mentioned {} in group '{}'", user_id, usernames.join(", "), group_name);
+
+    // Le menzioni lette vengono salvate dopo aver rilasciato il lock, per non bloccare gli altri client sul database
+    let mut already_read = Vec::new();
+    {
+        let users = connected_users.lock().unwrap();
+        for mentioned_id in mentioned_ids {
+            match users.get(&mentioned_id) {
+                Some((_, current_group)) if current_group.as_deref() == Some(group_id) => {
+                    already_read.push(mentioned_id);
+                }
+                Some((user_outbound, _)) => {
+                    user_outbound.try_send(ProtocolMessage::Mentioned {
+                        group: group_name.to_string(),
+                        message: message.clone(),
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+    for mentioned_id in already_read {
+        let _ = database.mark_mentions_read(&mentioned_id, Some(group_id));
+    }
+}
+
+#[allow(dead_code)]
+fn debug_print_connected_users(connected_users: &ConnectedUsers) {
     let users_map = connected_users.lock().unwrap();
     println!("🔍 DEBUG: Connected users state:");
     
@@ -169,28 +667,73 @@ fn debug_print_connected_users(connected_users: &Arc<Mutex<HashMap<String, (TcpS
 fn process_message(
     message: ProtocolMessage,
     database: &Database,
-    connected_users: &Arc<Mutex<HashMap<String, (TcpStream, Option<String>)>>>,
-    current_user_id: &mut Option<String>,
-    stream: &TcpStream,
+    connected_users: &ConnectedUsers,
+    session: &mut ClientSession,
+    outbound: &Outbound,
+    config: &ServerConfig,
 ) -> ProtocolMessage {
+    // L'handshake Hello/Welcome è obbligatorio prima di qualsiasi altro messaggio
+    if let ProtocolMessage::Hello { protocol_version, client_name, capabilities } = message {
+        if session.capabilities.is_some() {
+            return ProtocolMessage::Error {
+                message: "Handshake already completed".to_string(),
+            };
+        }
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+            println!("⛔ Rejected client '{}' with protocol version {}", client_name, protocol_version);
+            return ProtocolMessage::Error {
+                message: format!(
+                    "Unsupported protocol version {}: this server supports versions {} to {}. Please update your client.",
+                    protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            };
+        }
+        let negotiated = protocol::negotiate_capabilities(&capabilities);
+        println!("🤝 Client '{}' (protocol v{}) negotiated capabilities: {:?}", client_name, protocol_version, negotiated);
+        session.capabilities = Some(negotiated.clone());
+        return ProtocolMessage::Welcome {
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: negotiated,
+        };
+    }
+
+    if session.capabilities.is_none() {
+        return ProtocolMessage::Error {
+            message: format!("Handshake required: send Hello with protocol version {} before any other message", PROTOCOL_VERSION),
+        };
+    }
+
+    // Le funzionalità opzionali sono disponibili solo se negoziate
+    if let Some(capability) = message.required_capability() {
+        if !session.supports(capability) {
+            return ProtocolMessage::Error {
+                message: format!("Capability '{}' was not negotiated for this connection", capability),
+            };
+        }
+    }
+
+    let current_user_id = &mut session.user_id;
     match message {
         ProtocolMessage::Register { username, password } => {
             match database.register_user(&username, &password) {
                 Ok(user_id) => {
                     *current_user_id = Some(user_id.clone());
-                    connected_users.lock().unwrap().insert(user_id.clone(), (stream.try_clone().unwrap(), None));
+                    connected_users.lock().unwrap().insert(user_id.clone(), (outbound.with_capabilities(&session.capabilities), None));
                     println!("✅ User {} registered and connected", user_id);
                     //debug_print_connected_users(connected_users);
+                    session.token = issue_session_token(database, &user_id, config.session_ttl_secs);
                     ProtocolMessage::AuthResult {
                         success: true,
                         user_id: Some(user_id),
                         message: "Registration successful!".to_string(),
+                        session_token: session.token.clone(),
                     }
                 }
                 Err(e) => ProtocolMessage::AuthResult {
                     success: false,
                     user_id: None,
                     message: format!("Registration failed: {}", e),
+                    session_token: None,
                 },
             }
         }
@@ -199,28 +742,89 @@ fn process_message(
             match database.login_user(&username, &password) {
                 Ok(user_id) => {
                     *current_user_id = Some(user_id.clone());
-                    connected_users.lock().unwrap().insert(user_id.clone(), (stream.try_clone().unwrap(), None));
+                    connected_users.lock().unwrap().insert(user_id.clone(), (outbound.with_capabilities(&session.capabilities), None));
                     println!("✅ User {} logged in and connected", user_id);
                     //debug_print_connected_users(connected_users);
+                    session.token = issue_session_token(database, &user_id, config.session_ttl_secs);
                     ProtocolMessage::AuthResult {
                         success: true,
                         user_id: Some(user_id),
                         message: "Login successful!".to_string(),
+                        session_token: session.token.clone(),
                     }
                 }
                 Err(e) => ProtocolMessage::AuthResult {
                     success: false,
                     user_id: None,
                     message: format!("Login failed: {}", e),
+                    session_token: None,
+                },
+            }
+        }
+
+        ProtocolMessage::Resume { token } => {
+            match database.resume_session(&token, config.session_ttl_secs) {
+                Ok((user_id, group_name)) => {
+                    // Ripristina il gruppo in cui si trovava l'utente, se ne è ancora membro
+                    let group_id = group_name.as_ref().and_then(|name| database.get_group_id(name).ok());
+                    *current_user_id = Some(user_id.clone());
+                    session.token = Some(token);
+                    connected_users.lock().unwrap().insert(user_id.clone(), (outbound.with_capabilities(&session.capabilities), group_id.clone()));
+                    remember_session_group(database, &session.token, group_id.as_deref());
+                    if let Some(group_id) = &group_id {
+                        mark_group_read(database, &user_id, group_id);
+                    }
+                    println!("🔄 User {} resumed session (group: {:?})", user_id, group_name);
+
+                    let group = group_name.as_ref().and_then(|name| database.get_group(name).ok());
+                    let (group, recent_messages) = match group {
+                        Some(mut group) => {
+                            let recent_messages = database.get_recent_messages(&group.name, config.recent_messages)
+                                .unwrap_or_else(|_| Vec::new());
+                            group.name = database.conversation_name(&group.name, &user_id);
+                            (Some(group), recent_messages)
+                        }
+                        None => (None, Vec::new()),
+                    };
+
+                    ProtocolMessage::SessionResumed {
+                        user_id,
+                        group,
+                        recent_messages,
+                    }
+                }
+                Err(e) => ProtocolMessage::AuthResult {
+                    success: false,
+                    user_id: None,
+                    message: format!("Resume failed: {}", e),
+                    session_token: None,
                 },
             }
         }
 
-        ProtocolMessage::CreateGroup { name } => {
+        ProtocolMessage::Logout => {
+            if let Some(token) = session.token.take() {
+                if let Err(e) = database.revoke_session(&token) {
+                    eprintln!("❌ Failed to revoke session: {}", e);
+                }
+            }
+            if let Some(user_id) = current_user_id.take() {
+                connected_users.lock().unwrap().remove(&user_id);
+                println!("🔒 User {} logged out", user_id);
+            }
+            ProtocolMessage::Ok {
+                message: "Logged out".to_string(),
+            }
+        }
+
+        ProtocolMessage::CreateGroup { name, visibility } => {
             if let Some(user_id) = current_user_id {
-                match database.create_group(&name, user_id) {
+                match database.create_group(&name, user_id, visibility) {
                     Ok(_) => ProtocolMessage::Ok {
-                        message: format!("Group '{}' created successfully!", name),
+                        message: match visibility {
+                            GroupVisibility::Public => format!("Group '{}' created successfully!", name),
+                            GroupVisibility::Private => format!("Private group '{}' created successfully: members join by invitation only", name),
+                        },
                     },
                     Err(e) => ProtocolMessage::Error {
                         message: format!("Failed to create group: {}", e),
@@ -235,6 +839,10 @@ fn process_message(
         
         ProtocolMessage::JoinGroup { group_name } => {
             if let Some(user_id) = current_user_id {
+                // "@utente" è la conversazione diretta con quell'utente (ad esempio quando il client si riconnette)
+                if let Some(username) = group_name.strip_prefix('@') {
+                    return enter_direct_conversation(database, connected_users, &session.token, user_id, username, config);
+                }
                 match database.join_group(&group_name, user_id) {
                     Ok(_) => {
                         // Ottieni il group_id dal group_name
@@ -244,6 +852,11 @@ fn process_message(
                                 if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
                                     *current_group = Some(group_id.clone());
                                 }
+                                remember_session_group(database, &session.token, Some(&group_id));
+                                mark_group_read(database, user_id, &group_id);
+                                if let Err(e) = database.mark_mentions_read(user_id, Some(&group_id)) {
+                                    eprintln!("❌ Failed to mark mentions as read: {}", e);
+                                }
                                 println!("🏠 User {} joined group '{}' (ID: {})", user_id, group_name, group_id);
                                 //debug_print_connected_users(connected_users);
                             }
@@ -253,17 +866,15 @@ fn process_message(
                             }
                         }
                         
-                        // Recupera i messaggi recenti del gruppo (massimo 20)
-                        let recent_messages = database.get_recent_messages(&group_name, 20)
+                        // Recupera i messaggi recenti del gruppo (massimo recent_messages)
+                        let recent_messages = database.get_recent_messages(&group_name, config.recent_messages)
                             .unwrap_or_else(|_| Vec::new());
                         
-                        // Crea un oggetto Group temporaneo per la risposta
-                        let group = ruggine::common::Group {
-                            id: "temp_id".to_string(), // Non abbiamo l'ID qui, ma non è critico per il client
-                            name: group_name.clone(),
-                            members: Vec::new(),
-                            creator_id: "".to_string(),
-                            created_at: "".to_string(),
+                        let group = match database.get_group(&group_name) {
+                            Ok(group) => group,
+                            Err(e) => return ProtocolMessage::Error {
+                                message: format!("Failed to join group: {}", e),
+                            },
                         };
                         
                         ProtocolMessage::GroupJoined { 
@@ -307,9 +918,19 @@ fn process_message(
         }
 
         ProtocolMessage::ListGroupUsers { group_name } => {
-            if let Some(_user_id) = current_user_id {
-                match database.get_group_members(&group_name) {
-                    Ok(users) => ProtocolMessage::UserListResponse { users },
+            if let Some(user_id) = current_user_id {
+                match stored_group_name(database, user_id, &group_name).and_then(|name| database.get_group_members_with_roles(&name, user_id)) {
+                    Ok(members) if session.supports(capabilities::ROLES) => ProtocolMessage::GroupMemberList { group_name, members },
+                    // I client che non conoscono i ruoli ricevono la lista semplice con il ruolo tra parentesi
+                    Ok(members) => ProtocolMessage::UserListResponse {
+                        users: members
+                            .into_iter()
+                            .map(|member| match member.role {
+                                GroupRole::Member => member.username,
+                                role => format!("{} ({})", member.username, role.as_str()),
+                            })
+                            .collect(),
+                    },
                     Err(e) => ProtocolMessage::Error {
                         message: format!("Failed to get group members: {}", e),
                     },
@@ -321,14 +942,12 @@ fn process_message(
             }
         }
 
-        ProtocolMessage::InviteUser { username, group_name } => {
+        ProtocolMessage::ListPublicGroups => {
             if let Some(user_id) = current_user_id {
-                match database.invite_user_to_group(&group_name, &username, user_id) {
-                    Ok(_) => ProtocolMessage::Ok {
-                        message: format!("User '{}' invited to group '{}'!", username, group_name),
-                    },
+                match database.get_public_groups(user_id) {
+                    Ok(groups) => ProtocolMessage::PublicGroupList { groups },
                     Err(e) => ProtocolMessage::Error {
-                        message: format!("Failed to invite user: {}", e),
+                        message: format!("Failed to list public groups: {}", e),
                     },
                 }
             } else {
@@ -338,23 +957,17 @@ fn process_message(
             }
         }
 
-        ProtocolMessage::LeaveGroup { group_name } => {
+        ProtocolMessage::SetGroupVisibility { group_name, visibility } => {
             if let Some(user_id) = current_user_id {
-                match database.leave_group(&group_name, user_id) {
+                match database.set_group_visibility(&group_name, visibility, user_id) {
                     Ok(_) => {
-                        // Aggiorna il gruppo corrente dell'utente (torna nella home)
-                        if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
-                            *current_group = None;
-                        }
-                        println!("🚪 User {} left group '{}' and returned to home", user_id, group_name);
-                        //debug_print_connected_users(connected_users);
-                        
+                        println!("👁️ User {} made group '{}' {}", user_id, group_name, visibility.as_str());
                         ProtocolMessage::Ok {
-                            message: format!("Left group '{}'!", group_name),
+                            message: format!("Group '{}' is now {}", group_name, visibility.as_str()),
                         }
-                    },
+                    }
                     Err(e) => ProtocolMessage::Error {
-                        message: format!("Failed to leave group: {}", e),
+                        message: format!("Failed to change visibility: {}", e),
                     },
                 }
             } else {
@@ -363,18 +976,17 @@ fn process_message(
                 }
             }
         }
-        
-        ProtocolMessage::QuitGroup => {
+
+        ProtocolMessage::RenameGroup { group_name, new_name } => {
             if let Some(user_id) = current_user_id {
-                // Aggiorna il gruppo corrente dell'utente (torna nella home)
-                if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
-                    *current_group = None;
-                }
-                println!("🚪 User {} quit group and returned to home", user_id);
-                //debug_print_connected_users(connected_users);
-                
-                ProtocolMessage::Ok {
-                    message: "Left group and returned to home!".to_string(),
+                match database.rename_group(&group_name, &new_name, user_id) {
+                    Ok(group) => {
+                        println!("✏️ User {} renamed group '{}' to '{}'", user_id, group_name, group.name);
+                        group_updated(connected_users, user_id, group_name, group)
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to rename group: {}", e),
+                    },
                 }
             } else {
                 ProtocolMessage::Error {
@@ -382,18 +994,17 @@ fn process_message(
                 }
             }
         }
-        
-        ProtocolMessage::GoHome => {
+
+        ProtocolMessage::SetGroupTopic { group_name, topic } => {
             if let Some(user_id) = current_user_id {
-                // Aggiorna il gruppo corrente dell'utente (torna nella home)
-                if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
-                    *current_group = None;
-                }
-                println!("🏠 User {} returned to home", user_id);
-                //debug_print_connected_users(connected_users);
-                
-                ProtocolMessage::Ok {
-                    message: "Returned to home".to_string(),
+                match database.set_group_topic(&group_name, topic.as_deref(), user_id) {
+                    Ok(group) => {
+                        println!("📌 User {} changed the topic of group '{}'", user_id, group_name);
+                        group_updated(connected_users, user_id, group_name, group)
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to change topic: {}", e),
+                    },
                 }
             } else {
                 ProtocolMessage::Error {
@@ -401,63 +1012,464 @@ fn process_message(
                 }
             }
         }
-        
-        ProtocolMessage::Quit => {
+
+        ProtocolMessage::SetGroupDescription { group_name, description } => {
             if let Some(user_id) = current_user_id {
-                let user_id_for_log = user_id.clone();
-                connected_users.lock().unwrap().remove(user_id);
-                *current_user_id = None;
-                println!("👋 User {} quit the application", user_id_for_log);
-                //debug_print_connected_users(connected_users);
-            }
-            ProtocolMessage::Ok {
-                message: "Goodbye!".to_string(),
+                match database.set_group_description(&group_name, description.as_deref(), user_id) {
+                    Ok(group) => {
+                        println!("📝 User {} changed the description of group '{}'", user_id, group_name);
+                        group_updated(connected_users, user_id, group_name, group)
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to change description: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
             }
         }
 
-        ProtocolMessage::SendMessage { content, group_name } => {
+        ProtocolMessage::ArchiveGroup { group_name } => {
             if let Some(user_id) = current_user_id {
-                // Ricava il group_id dal group_name
-                let this_group_id = match database.get_group_id(&group_name) {
-                    Ok(id) => id,
-                    Err(e) => return ProtocolMessage::Error {
-                        message: format!("Failed to get group ID: {}", e),
+                match database.set_group_archived(&group_name, true, user_id) {
+                    Ok((group, member_ids)) => {
+                        println!("🗄️ User {} archived group '{}'", user_id, group.name);
+                        let archived_by = database.get_username(user_id).unwrap_or_default();
+                        notify_group_closed(connected_users, &group.id, &member_ids, user_id, ProtocolMessage::GroupArchived {
+                            group_name: group.name.clone(),
+                            archived_by,
+                        })
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to archive group: {}", e),
                     },
-                };
-
-                match database.send_message(&group_name, user_id, &content) {
-                    Ok(message) => {
-                        // Recupera i messaggi recenti del gruppo (massimo 20)
-                        let recent_messages = database.get_recent_messages(&group_name, 20)
-                            .unwrap_or_else(|_| Vec::new());
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::UnarchiveGroup { group_name } => {
+            if let Some(user_id) = current_user_id {
+                match database.set_group_archived(&group_name, false, user_id) {
+                    Ok((group, _)) => {
+                        println!("📤 User {} restored group '{}'", user_id, group.name);
+                        group_updated(connected_users, user_id, group_name, group)
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to restore group: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::DeleteGroup { group_name } => {
+            if let Some(user_id) = current_user_id {
+                match database.delete_group(&group_name, user_id) {
+                    Ok((group, member_ids)) => {
+                        println!("🗑️ User {} deleted group '{}' (ID: {})", user_id, group.name, group.id);
+                        let deleted_by = database.get_username(user_id).unwrap_or_default();
+                        notify_group_closed(connected_users, &group.id, &member_ids, user_id, ProtocolMessage::GroupDeleted {
+                            group_name: group.name.clone(),
+                            deleted_by,
+                        })
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to delete group: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::ListArchivedGroups => {
+            if let Some(user_id) = current_user_id {
+                match database.get_archived_groups(user_id) {
+                    Ok(groups) => ProtocolMessage::GroupListResponse { groups },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to get archived groups: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::InviteUser { username, group_name } => {
+            if let Some(user_id) = current_user_id {
+                match database.invite_user_to_group(&group_name, &username, user_id, config.invite_ttl_secs) {
+                    Ok(invite) => {
+                        println!("📨 User {} invited {} to group '{}'", user_id, username, group_name);
+                        // Se l'invitato è online riceve subito l'invito, altrimenti lo vedrà al prossimo login
+                        if let Some((invitee_outbound, _)) = connected_users.lock().unwrap().get(&invite.invited_user) {
+                            invitee_outbound.try_send(ProtocolMessage::InviteReceived { invite: invite.clone() });
+                        }
+                        ProtocolMessage::Ok {
+                            message: format!("Invitation sent to '{}' for group '{}'", username, group_name),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to invite user: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::ListInvites => {
+            if let Some(user_id) = current_user_id {
+                match database.get_pending_invites(user_id) {
+                    Ok(invites) => ProtocolMessage::InviteList { invites },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to get invitations: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::AcceptInvite { group_name } => {
+            if let Some(user_id) = current_user_id {
+                match database.accept_invite(&group_name, user_id) {
+                    Ok(_) => {
+                        println!("🤝 User {} accepted the invitation to group '{}'", user_id, group_name);
+                        ProtocolMessage::Ok {
+                            message: format!("You are now a member of '{}': use /join {} to enter it", group_name, group_name),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to accept invitation: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::DeclineInvite { group_name } => {
+            if let Some(user_id) = current_user_id {
+                match database.decline_invite(&group_name, user_id) {
+                    Ok(_) => ProtocolMessage::Ok {
+                        message: format!("Invitation to '{}' declined", group_name),
+                    },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to decline invitation: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::PromoteMember { group_name, username } => {
+            if let Some(user_id) = current_user_id {
+                match database.set_member_role(&group_name, &username, GroupRole::Admin, user_id) {
+                    Ok(_) => {
+                        println!("⭐ User {} promoted {} to admin of group '{}'", user_id, username, group_name);
+                        ProtocolMessage::Ok {
+                            message: format!("'{}' is now an admin of '{}'", username, group_name),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to promote user: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::DemoteMember { group_name, username } => {
+            if let Some(user_id) = current_user_id {
+                match database.set_member_role(&group_name, &username, GroupRole::Member, user_id) {
+                    Ok(_) => {
+                        println!("🔻 User {} demoted {} to member of group '{}'", user_id, username, group_name);
+                        ProtocolMessage::Ok {
+                            message: format!("'{}' is no longer an admin of '{}'", username, group_name),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to demote user: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::KickMember { group_name, username } => {
+            if let Some(user_id) = current_user_id {
+                match database.kick_member(&group_name, &username, user_id) {
+                    Ok(kicked_id) => {
+                        println!("👢 User {} kicked {} from group '{}'", user_id, username, group_name);
+                        notify_removed_from_group(database, connected_users, &kicked_id, &group_name, user_id, false, None);
+                        ProtocolMessage::Ok {
+                            message: format!("'{}' was removed from '{}'", username, group_name),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to remove user: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::BanMember { group_name, username, duration_secs } => {
+            if let Some(user_id) = current_user_id {
+                // La durata viene validata da ban_member
+                match database.ban_member(&group_name, &username, user_id, duration_secs) {
+                    Ok(ban) => {
+                        println!("🚫 User {} banned {} from group '{}' (until {:?})", user_id, username, group_name, ban.expires_at);
+                        notify_removed_from_group(database, connected_users, &ban.user_id, &group_name, user_id, true, ban.expires_at);
+                        let until = ban.expires_at
+                            .map(|until| format!("until {}", until.format("%Y-%m-%d %H:%M UTC")))
+                            .unwrap_or_else(|| "permanently".to_string());
+                        ProtocolMessage::Ok {
+                            message: format!("'{}' is banned from '{}' {}", username, group_name, until),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to ban user: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::UnbanMember { group_name, username } => {
+            if let Some(user_id) = current_user_id {
+                match database.unban_member(&group_name, &username, user_id) {
+                    Ok(_) => {
+                        println!("✅ User {} lifted the ban of {} from group '{}'", user_id, username, group_name);
+                        ProtocolMessage::Ok {
+                            message: format!("'{}' is no longer banned from '{}': invite them to let them back in", username, group_name),
+                        }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to unban user: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::OpenDirect { username } => {
+            if let Some(user_id) = current_user_id {
+                enter_direct_conversation(database, connected_users, &session.token, user_id, &username, config)
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::ListDirects => {
+            if let Some(user_id) = current_user_id {
+                match database.get_direct_conversations(user_id) {
+                    Ok(conversations) => ProtocolMessage::DirectList { conversations },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to get direct conversations: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::LeaveGroup { group_name } => {
+            if let Some(user_id) = current_user_id {
+                if group_name.starts_with('@') {
+                    return ProtocolMessage::Error {
+                        message: "Direct conversations cannot be left: use /home to go back".to_string(),
+                    };
+                }
+                match database.leave_group(&group_name, user_id) {
+                    Ok(_) => {
+                        // Aggiorna il gruppo corrente dell'utente (torna nella home)
+                        if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
+                            *current_group = None;
+                        }
+                        remember_session_group(database, &session.token, None);
+                        println!("🚪 User {} left group '{}' and returned to home", user_id, group_name);
+                        //debug_print_connected_users(connected_users);
                         
-                        // Invia in broadcast a tutti i membri del gruppo. Da connected_users vedo chi è connesso a quel group_id e invia un ProtocolMessage::ReloadMessages
-                        for (connected_user_id, (user_stream, current_group)) in connected_users.lock().unwrap().iter_mut() {
-                            if let Some(group_id) = current_group {
-                                if group_id == &this_group_id && connected_user_id != user_id {
-                                    let response = ProtocolMessage::ReloadMessages {
-                                        recent_messages: recent_messages.clone(),
-                                    };
-                                    if let Ok(response_data) = response.to_wire_format() {
-                                        if let Err(e) = user_stream.write_all(response_data.as_bytes()) {
-                                            eprintln!("❌ Error sending message to {}: {}", connected_user_id, e);
-                                        }
-                                        if let Err(e) = user_stream.flush() {
-                                            eprintln!("❌ Error flushing to {}: {}", connected_user_id, e);
-                                        }
-                                    }
+                        ProtocolMessage::Ok {
+                            message: format!("Left group '{}'!", group_name),
+                        }
+                    },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to leave group: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+        
+        ProtocolMessage::QuitGroup => {
+            if let Some(user_id) = current_user_id {
+                // Aggiorna il gruppo corrente dell'utente (torna nella home)
+                if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
+                    *current_group = None;
+                }
+                remember_session_group(database, &session.token, None);
+                println!("🚪 User {} quit group and returned to home", user_id);
+                //debug_print_connected_users(connected_users);
+                
+                ProtocolMessage::Ok {
+                    message: "Left group and returned to home!".to_string(),
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+        
+        ProtocolMessage::GoHome => {
+            if let Some(user_id) = current_user_id {
+                // Aggiorna il gruppo corrente dell'utente (torna nella home)
+                if let Some((_stream_ref, current_group)) = connected_users.lock().unwrap().get_mut(user_id) {
+                    *current_group = None;
+                }
+                remember_session_group(database, &session.token, None);
+                println!("🏠 User {} returned to home", user_id);
+                //debug_print_connected_users(connected_users);
+                
+                ProtocolMessage::Ok {
+                    message: "Returned to home".to_string(),
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+        
+        ProtocolMessage::Quit => {
+            if let Some(user_id) = current_user_id {
+                let user_id_for_log = user_id.clone();
+                connected_users.lock().unwrap().remove(user_id);
+                *current_user_id = None;
+                println!("👋 User {} quit the application", user_id_for_log);
+                //debug_print_connected_users(connected_users);
+            }
+            ProtocolMessage::Ok {
+                message: "Goodbye!".to_string(),
+            }
+        }
+
+        ProtocolMessage::SendMessage { content, group_name, reply_to } => {
+            if let Some(user_id) = current_user_id {
+                // "@utente" indica la conversazione diretta con quell'utente
+                let direct = match group_name.strip_prefix('@') {
+                    Some(username) => match database.get_direct_conversation(user_id, username) {
+                        Ok(conversation) => Some(conversation),
+                        Err(e) => return ProtocolMessage::Error {
+                            message: format!("Failed to send message: {}", e),
+                        },
+                    },
+                    None => None,
+                };
+                let stored_name = direct.as_ref().map_or(group_name.clone(), |conversation| conversation.group_name.clone());
+
+                // Ricava il group_id dal group_name
+                let this_group_id = match database.get_group_id(&stored_name) {
+                    Ok(id) => id,
+                    Err(e) => return ProtocolMessage::Error {
+                        message: format!("Failed to get group ID: {}", e),
+                    },
+                };
+
+                match database.send_message(&stored_name, user_id, &content, reply_to.as_deref()) {
+                    Ok(message) => {
+                        // Recupera solo il messaggio appena inserito (con lo username dell'autore)
+                        let chat_message = match database.get_message(&message[0]) {
+                            Ok(chat_message) => chat_message,
+                            Err(e) => return ProtocolMessage::Error {
+                                message: format!("Failed to load sent message: {}", e),
+                            },
+                        };
+                        
+                        match direct {
+                            // Il destinatario lo riceve ovunque si trovi, come "@mittente"
+                            Some(conversation) => {
+                                if let Some((partner_outbound, _)) = connected_users.lock().unwrap().get(&conversation.partner_id) {
+                                    partner_outbound.try_send(ProtocolMessage::NewMessage {
+                                        group: format!("@{}", chat_message.username),
+                                        message: chat_message.clone(),
+                                    });
                                 }
                             }
+                            // Invia in broadcast il nuovo messaggio agli altri membri presenti nel gruppo
+                            None => {
+                                broadcast_to_group(
+                                    connected_users,
+                                    &this_group_id,
+                                    Some(user_id),
+                                    ProtocolMessage::NewMessage {
+                                        group: group_name.clone(),
+                                        message: chat_message.clone(),
+                                    },
+                                );
+                                notify_mentions(database, connected_users, &group_name, &this_group_id, user_id, &chat_message);
+                            }
                         }
 
-                        ProtocolMessage::MessageReceived {
-                            message: Message::new(
-                                message[0].clone(), 
-                                user_id.clone(),  
-                                message[1].clone(), 
-                                content.clone()   
-                            ),
-                            recent_messages,
+                        // Chi si trova nel gruppo (autore compreso) ha già visto il messaggio
+                        let present: Vec<String> = connected_users.lock().unwrap()
+                            .iter()
+                            .filter(|(_, (_, current_group))| current_group.as_deref() == Some(this_group_id.as_str()))
+                            .map(|(present_id, _)| present_id.clone())
+                            .collect();
+                        if let Err(e) = database.mark_message_read(&present, &this_group_id, &chat_message.id, &message[4]) {
+                            eprintln!("❌ Failed to update last read message: {}", e);
+                        }
+
+                        ProtocolMessage::NewMessage {
+                            group: group_name,
+                            message: chat_message,
                         }
                     }
                     Err(e) => ProtocolMessage::Error {
@@ -471,6 +1483,146 @@ fn process_message(
             }
         }
         
+        ProtocolMessage::EditMessage { message_id, content } => {
+            if let Some(user_id) = current_user_id {
+                match database.edit_message(&message_id, user_id, &content) {
+                    Ok((group, message)) => {
+                        println!("✏️ User {} edited message {} in group '{}'", user_id, message.id, group.name);
+                        broadcast_message_event(database, connected_users, &group, user_id, |group| {
+                            ProtocolMessage::MessageUpdated { group, message: message.clone() }
+                        })
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to edit message: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::DeleteMessage { message_id } => {
+            if let Some(user_id) = current_user_id {
+                match database.delete_message(&message_id, user_id) {
+                    Ok((group, message)) => {
+                        println!("🗑️ User {} deleted message {} in group '{}'", user_id, message.id, group.name);
+                        broadcast_message_event(database, connected_users, &group, user_id, |group| {
+                            ProtocolMessage::MessageUpdated { group, message: message.clone() }
+                        })
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to delete message: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::React { message_id, emoji } => {
+            if let Some(user_id) = current_user_id {
+                update_reaction(database, connected_users, user_id, &message_id, &emoji, true)
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::Unreact { message_id, emoji } => {
+            if let Some(user_id) = current_user_id {
+                update_reaction(database, connected_users, user_id, &message_id, &emoji, false)
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::FetchThread { message_id } => {
+            if let Some(user_id) = current_user_id {
+                match database.get_thread(&message_id, user_id) {
+                    Ok((group, messages)) => ProtocolMessage::Thread {
+                        group: database.conversation_name(&group.name, user_id),
+                        messages,
+                    },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to load thread: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::ListMentions => {
+            if let Some(user_id) = current_user_id {
+                match database.get_unread_mentions(user_id) {
+                    Ok(mentions) => {
+                        if let Err(e) = database.mark_mentions_read(user_id, None) {
+                            eprintln!("❌ Failed to mark mentions as read: {}", e);
+                        }
+                        ProtocolMessage::MentionList { mentions }
+                    }
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to get mentions: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+
+        ProtocolMessage::FetchHistory { group_name, before, limit, after } => {
+            if let Some(user_id) = current_user_id {
+                let limit = limit.clamp(1, config.max_history_page);
+                let history = stored_group_name(database, user_id, &group_name)
+                    .and_then(|name| database.get_message_history(&name, user_id, before.as_deref(), after.as_deref(), limit));
+                match history {
+                    Ok((messages, has_more)) => ProtocolMessage::HistoryPage { messages, has_more },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Failed to fetch history: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+        
+        ProtocolMessage::SearchMessages { query, group_name, limit } => {
+            if let Some(user_id) = current_user_id {
+                let limit = limit.clamp(1, config.max_search_results);
+                let group_filter = match group_name.map(|name| stored_group_name(database, user_id, &name)).transpose() {
+                    Ok(group_filter) => group_filter,
+                    Err(e) => return ProtocolMessage::Error {
+                        message: format!("Search failed: {}", e),
+                    },
+                };
+                match database.search_messages(user_id, &query, group_filter.as_deref(), limit) {
+                    Ok(results) => ProtocolMessage::SearchResults { query, results },
+                    Err(e) => ProtocolMessage::Error {
+                        message: format!("Search failed: {}", e),
+                    },
+                }
+            } else {
+                ProtocolMessage::Error {
+                    message: "Not authenticated".to_string(),
+                }
+            }
+        }
+        
+        ProtocolMessage::Ping => ProtocolMessage::Pong,
+        
         _ => ProtocolMessage::Error {
             message: "Command not implemented yet".to_string(),
         },
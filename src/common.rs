@@ -28,6 +28,34 @@ impl User {
     }
 }
 
+/// Chi può entrare in un gruppo
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupVisibility {
+    /// Compare in /browse e chiunque può entrare con /join
+    #[default]
+    Public,
+    /// Si entra solo su invito
+    Private,
+}
+
+impl GroupVisibility {
+    /// Valore salvato nella colonna visibility di groups
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupVisibility::Public => "public",
+            GroupVisibility::Private => "private",
+        }
+    }
+
+    pub fn from_db(value: &str) -> Self {
+        match value {
+            "private" => GroupVisibility::Private,
+            _ => GroupVisibility::Public,
+        }
+    }
+}
+
 /// Struttura che rappresenta un gruppo
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Group {
@@ -36,6 +64,19 @@ pub struct Group {
     pub members: Vec<UserId>,
     pub creator_id: UserId,
     pub created_at: String,
+    #[serde(default)]
+    pub visibility: GroupVisibility,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Argomento corrente, mostrato a chi entra nel gruppo
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// Gruppo archiviato: i membri possono leggerlo ma non scriverci
+    #[serde(default)]
+    pub archived: bool,
+    /// Messaggi non ancora letti da chi ha chiesto l'elenco dei gruppi (solo in GroupListResponse)
+    #[serde(default)]
+    pub unread_count: u32,
 }
 
 impl Group {
@@ -46,6 +87,11 @@ impl Group {
             members: vec![creator_id.clone()],
             creator_id,
             created_at: Utc::now().to_rfc3339(),
+            visibility: GroupVisibility::default(),
+            description: None,
+            topic: None,
+            archived: false,
+            unread_count: 0,
         }
     }
 
@@ -82,26 +128,143 @@ impl Message {
     }
 }
 
-/// Struttura per l'invito a un gruppo
+/// Struttura per l'invito a un gruppo, in attesa che l'invitato lo accetti o lo rifiuti
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GroupInvite {
     pub id: String,
     pub group_id: GroupId,
+    pub group_name: String,
     pub invited_user: UserId,
     pub inviter: UserId,
+    pub inviter_username: String,
     pub timestamp: DateTime<Utc>,
+    /// Scadenza dell'invito (None = non scade)
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 impl GroupInvite {
-    pub fn new(group_id: GroupId, invited_user: UserId, inviter: UserId) -> Self {
+    pub fn new(group_id: GroupId, group_name: String, invited_user: UserId, inviter: UserId, inviter_username: String) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
             group_id,
+            group_name,
             invited_user,
             inviter,
+            inviter_username,
             timestamp: Utc::now(),
+            expires_at: None,
+        }
+    }
+}
+
+/// Ruolo di un membro all'interno di un gruppo (in ordine crescente di privilegi)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    Member,
+    Admin,
+    /// Il creatore del gruppo (uno solo per gruppo)
+    Owner,
+}
+
+impl GroupRole {
+    /// Valore salvato nella colonna role di group_memberships
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupRole::Member => "member",
+            GroupRole::Admin => "admin",
+            GroupRole::Owner => "owner",
         }
     }
+
+    /// Legge il ruolo dal database; valori sconosciuti valgono come semplice membro
+    pub fn from_db(value: &str) -> Self {
+        match value {
+            "owner" => GroupRole::Owner,
+            "admin" => GroupRole::Admin,
+            _ => GroupRole::Member,
+        }
+    }
+
+    /// Può eseguire l'azione indicata?
+    pub fn allows(&self, action: GroupAction) -> bool {
+        *self >= action.required_role()
+    }
+}
+
+/// Operazioni su un gruppo che richiedono un ruolo minimo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupAction {
+    Invite,
+    Kick,
+    Rename,
+    EditInfo,
+    ChangeVisibility,
+    Archive,
+    Delete,
+    DeleteMessages,
+    ChangeRoles,
+}
+
+impl GroupAction {
+    pub fn required_role(&self) -> GroupRole {
+        match self {
+            GroupAction::Invite
+            | GroupAction::Kick
+            | GroupAction::Rename
+            | GroupAction::EditInfo
+            | GroupAction::ChangeVisibility
+            | GroupAction::DeleteMessages => GroupRole::Admin,
+            GroupAction::Archive | GroupAction::Delete | GroupAction::ChangeRoles => GroupRole::Owner,
+        }
+    }
+
+    /// Messaggio d'errore per chi non ha i permessi necessari
+    pub fn denied_message(&self) -> String {
+        let who = match self.required_role() {
+            GroupRole::Owner => "the group owner",
+            _ => "the owner or an admin",
+        };
+        let what = match self {
+            GroupAction::Invite => "invite users",
+            GroupAction::Kick => "remove members",
+            GroupAction::Rename => "rename the group",
+            GroupAction::EditInfo => "change the group topic and description",
+            GroupAction::ChangeVisibility => "change the group visibility",
+            GroupAction::Archive => "archive the group",
+            GroupAction::Delete => "delete the group",
+            GroupAction::DeleteMessages => "delete messages of other members",
+            GroupAction::ChangeRoles => "change member roles",
+        };
+        format!("Only {} can {}", who, what)
+    }
+}
+
+/// Membro di un gruppo con il suo ruolo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub username: String,
+    pub role: GroupRole,
+}
+
+/// Gruppo pubblico elencato da /browse
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupSummary {
+    pub name: String,
+    pub description: Option<String>,
+    pub member_count: u32,
+    /// L'utente che ha chiesto l'elenco è già membro del gruppo
+    pub is_member: bool,
+}
+
+/// Ban di un utente da un gruppo
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupBan {
+    pub group_id: GroupId,
+    pub user_id: UserId,
+    pub banned_by: UserId,
+    /// Fine del ban (None = permanente)
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 /// Statistiche delle performance del server
@@ -135,4 +298,62 @@ pub struct ChatMessage {
     pub content: String,
     pub username: String,
     pub timestamp: String,
+    /// Data dell'ultima modifica (None = mai modificato)
+    #[serde(default)]
+    pub edited_at: Option<String>,
+    /// Messaggio eliminato: resta come segnaposto, senza contenuto
+    #[serde(default)]
+    pub deleted: bool,
+    /// Messaggio a cui questo risponde
+    #[serde(default)]
+    pub reply_to: Option<QuotedMessage>,
+    /// Reazioni ricevute, nell'ordine in cui sono comparse
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+}
+
+/// Numero di utenti che hanno reagito a un messaggio con la stessa emoji
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: u32,
+}
+
+/// Messaggio citato in una risposta
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotedMessage {
+    pub id: String,
+    pub username: String,
+    /// None se il messaggio citato è stato eliminato
+    pub content: Option<String>,
+}
+
+/// Conversazione diretta vista da uno dei due utenti
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectConversation {
+    pub group_id: GroupId,
+    /// Nome interno del gruppo che contiene i messaggi
+    pub group_name: String,
+    pub partner_id: UserId,
+    pub partner_username: String,
+    pub last_message: Option<ChatMessage>,
+}
+
+/// Messaggio in cui l'utente è stato menzionato
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub group_name: String,
+    pub message: ChatMessage,
+}
+
+/// Delimitatori dei termini trovati negli snippet di ricerca
+pub const SNIPPET_MATCH_START: &str = "\u{2}";
+pub const SNIPPET_MATCH_END: &str = "\u{3}";
+
+/// Risultato di una ricerca nei messaggi
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub group_name: String,
+    pub message: ChatMessage,
+    pub snippet: String,
 }
@@ -0,0 +1,355 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::database::{MAX_INVITE_TTL_SECS, MAX_SESSION_TTL_SECS};
+
+/// File di configurazione letto se non ne viene indicato un altro
+pub const DEFAULT_CONFIG_FILE: &str = "ruggine.toml";
+
+/// Intervallo massimo tra due righe di statistiche (1 giorno)
+pub const MAX_STATS_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// Intervallo massimo del heartbeat (1 ora)
+pub const MAX_HEARTBEAT_SECS: u64 = 60 * 60;
+
+/// Cosa fare quando la coda in uscita di un client è piena
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueFullPolicy {
+    /// Scarta il messaggio destinato al client lento
+    Drop,
+    /// Chiude la connessione del client lento
+    Disconnect,
+}
+
+impl std::str::FromStr for QueueFullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "drop" => Ok(QueueFullPolicy::Drop),
+            "disconnect" => Ok(QueueFullPolicy::Disconnect),
+            other => Err(format!("Unknown queue full policy '{}' (expected 'drop' or 'disconnect')", other)),
+        }
+    }
+}
+
+/// Configurazione del server.
+/// I valori vengono presi, in ordine di priorità crescente, dai default, dal file TOML,
+/// dalle variabili d'ambiente RUGGINE_* e dalle opzioni da riga di comando.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Indirizzo su cui il server accetta connessioni
+    pub bind_address: String,
+    /// Percorso del database SQLite
+    pub database_path: PathBuf,
+    /// File su cui vengono aggiunte le statistiche di performance
+    pub performance_log: PathBuf,
+    /// Intervallo tra due righe di statistiche
+    pub stats_interval_secs: u64,
+    /// Messaggi recenti inviati entrando in un gruppo o riprendendo una sessione
+    pub recent_messages: u32,
+    /// Numero massimo di messaggi restituiti da una singola FetchHistory
+    pub max_history_page: u32,
+    /// Numero massimo di risultati restituiti da una SearchMessages
+    pub max_search_results: u32,
+    /// Durata di una sessione (prolungata a ogni Resume)
+    pub session_ttl_secs: i64,
+    /// Dimensione della coda in uscita di ogni connessione
+    pub outbound_queue: usize,
+    /// Cosa fare quando la coda in uscita è piena
+    pub queue_full_policy: QueueFullPolicy,
+    /// Intervallo di inattività dopo cui il server invia un Ping (e tempo massimo per inviare Hello)
+    pub heartbeat_secs: u64,
+    /// Intervalli di inattività prima di chiudere la connessione
+    pub heartbeat_max_missed: u32,
+    /// Validità degli inviti ai gruppi (None = non scadono)
+    pub invite_ttl_secs: Option<i64>,
+    /// Certificato TLS (PEM); se assente il server accetta connessioni TCP in chiaro
+    pub tls_cert: Option<PathBuf>,
+    /// Chiave privata del certificato TLS (PEM)
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1:8080".to_string(),
+            database_path: PathBuf::from("ruggine.db"),
+            performance_log: PathBuf::from("server_performance.log"),
+            stats_interval_secs: 120,
+            recent_messages: 20,
+            max_history_page: 100,
+            max_search_results: 50,
+            session_ttl_secs: 7 * 24 * 60 * 60,
+            outbound_queue: 256,
+            queue_full_policy: QueueFullPolicy::Drop,
+            heartbeat_secs: 30,
+            heartbeat_max_missed: 3,
+            invite_ttl_secs: None,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+/// Opzioni da riga di comando del server (ognuna ha anche una variabile d'ambiente)
+#[derive(Debug, Parser)]
+#[command(name = "server", version, about = "Ruggine chat server")]
+pub struct ServerArgs {
+    /// File di configurazione TOML (default: ruggine.toml se presente)
+    #[arg(short, long, env = "RUGGINE_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Indirizzo di ascolto, es. 0.0.0.0:8080
+    #[arg(short, long, env = "RUGGINE_BIND")]
+    pub bind: Option<String>,
+    /// Percorso del database SQLite
+    #[arg(long, env = "RUGGINE_DB")]
+    pub db: Option<PathBuf>,
+    /// File delle statistiche di performance
+    #[arg(long, env = "RUGGINE_PERFORMANCE_LOG")]
+    pub performance_log: Option<PathBuf>,
+    /// Secondi tra due righe di statistiche
+    #[arg(long, env = "RUGGINE_STATS_INTERVAL_SECS")]
+    pub stats_interval_secs: Option<u64>,
+    /// Messaggi recenti inviati entrando in un gruppo
+    #[arg(long, env = "RUGGINE_RECENT_MESSAGES")]
+    pub recent_messages: Option<u32>,
+    /// Massimo numero di messaggi per pagina di cronologia
+    #[arg(long, env = "RUGGINE_MAX_HISTORY_PAGE")]
+    pub max_history_page: Option<u32>,
+    /// Massimo numero di risultati di ricerca
+    #[arg(long, env = "RUGGINE_MAX_SEARCH_RESULTS")]
+    pub max_search_results: Option<u32>,
+    /// Durata delle sessioni in secondi
+    #[arg(long, env = "RUGGINE_SESSION_TTL_SECS")]
+    pub session_ttl_secs: Option<i64>,
+    /// Dimensione della coda in uscita di ogni connessione
+    #[arg(long, env = "RUGGINE_OUTBOUND_QUEUE")]
+    pub outbound_queue: Option<usize>,
+    /// Policy con coda in uscita piena: drop o disconnect
+    #[arg(long, env = "RUGGINE_QUEUE_FULL_POLICY")]
+    pub queue_full_policy: Option<QueueFullPolicy>,
+    /// Secondi di inattività prima di inviare un Ping
+    #[arg(long, env = "RUGGINE_HEARTBEAT_SECS")]
+    pub heartbeat_secs: Option<u64>,
+    /// Ping senza risposta prima di chiudere la connessione
+    #[arg(long, env = "RUGGINE_HEARTBEAT_MAX_MISSED")]
+    pub heartbeat_max_missed: Option<u32>,
+    /// Secondi dopo cui un invito non accettato scade
+    #[arg(long, env = "RUGGINE_INVITE_TTL_SECS")]
+    pub invite_ttl_secs: Option<i64>,
+    /// Certificato TLS in formato PEM (abilita TLS insieme a --tls-key)
+    #[arg(long, env = "RUGGINE_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// Chiave privata TLS in formato PEM
+    #[arg(long, env = "RUGGINE_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    /// Legge la configurazione combinando file, variabili d'ambiente e riga di comando
+    pub fn load() -> Result<Self, String> {
+        Self::from_args(ServerArgs::parse())
+    }
+
+    pub fn from_args(args: ServerArgs) -> Result<Self, String> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        if let Some(bind) = args.bind { config.bind_address = bind; }
+        if let Some(db) = args.db { config.database_path = db; }
+        if let Some(log) = args.performance_log { config.performance_log = log; }
+        if let Some(secs) = args.stats_interval_secs { config.stats_interval_secs = secs; }
+        if let Some(count) = args.recent_messages { config.recent_messages = count; }
+        if let Some(count) = args.max_history_page { config.max_history_page = count; }
+        if let Some(count) = args.max_search_results { config.max_search_results = count; }
+        if let Some(secs) = args.session_ttl_secs { config.session_ttl_secs = secs; }
+        if let Some(capacity) = args.outbound_queue { config.outbound_queue = capacity; }
+        if let Some(policy) = args.queue_full_policy { config.queue_full_policy = policy; }
+        if let Some(secs) = args.heartbeat_secs { config.heartbeat_secs = secs; }
+        if let Some(missed) = args.heartbeat_max_missed { config.heartbeat_max_missed = missed; }
+        if let Some(secs) = args.invite_ttl_secs { config.invite_ttl_secs = Some(secs); }
+        if let Some(cert) = args.tls_cert { config.tls_cert = Some(cert); }
+        if let Some(key) = args.tls_key { config.tls_key = Some(key); }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Legge un file TOML; le chiavi mancanti prendono il valore di default
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file '{}': {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("Invalid config file '{}': {}", path.display(), e))
+    }
+
+    /// Controlla che i valori abbiano senso prima di avviare il server
+    pub fn validate(&self) -> Result<(), String> {
+        self.bind_address.parse::<SocketAddr>()
+            .map_err(|_| format!("Invalid bind_address '{}': expected IP:PORT, e.g. 127.0.0.1:8080", self.bind_address))?;
+
+        check_parent_dir("database_path", &self.database_path)?;
+        check_parent_dir("performance_log", &self.performance_log)?;
+
+        let positive = [
+            ("stats_interval_secs", self.stats_interval_secs),
+            ("recent_messages", self.recent_messages as u64),
+            ("max_history_page", self.max_history_page as u64),
+            ("max_search_results", self.max_search_results as u64),
+            ("outbound_queue", self.outbound_queue as u64),
+            ("heartbeat_secs", self.heartbeat_secs),
+            ("heartbeat_max_missed", self.heartbeat_max_missed as u64),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(format!("Invalid {}: must be greater than 0", name));
+            }
+        }
+        let bounded = [
+            ("stats_interval_secs", self.stats_interval_secs, MAX_STATS_INTERVAL_SECS),
+            ("heartbeat_secs", self.heartbeat_secs, MAX_HEARTBEAT_SECS),
+        ];
+        for (name, value, max) in bounded {
+            if value > max {
+                return Err(format!("Invalid {} {}: must be at most {}", name, value, max));
+            }
+        }
+        if self.session_ttl_secs <= 0 || self.session_ttl_secs > MAX_SESSION_TTL_SECS {
+            return Err(format!("Invalid session_ttl_secs {}: must be between 1 and {}", self.session_ttl_secs, MAX_SESSION_TTL_SECS));
+        }
+        if let Some(secs) = self.invite_ttl_secs.filter(|secs| *secs <= 0 || *secs > MAX_INVITE_TTL_SECS) {
+            return Err(format!(
+                "Invalid invite_ttl_secs {}: must be between 1 and {} (omit it for invitations that never expire)",
+                secs, MAX_INVITE_TTL_SECS
+            ));
+        }
+
+        match (&self.tls_cert, &self.tls_key) {
+            (None, None) => {}
+            (Some(cert), Some(key)) => {
+                for (name, path) in [("tls_cert", cert), ("tls_key", key)] {
+                    if !path.is_file() {
+                        return Err(format!("Invalid {} '{}': file does not exist", name, path.display()));
+                    }
+                }
+            }
+            _ => return Err("TLS needs both tls_cert and tls_key".to_string()),
+        }
+        Ok(())
+    }
+
+    pub fn stats_interval(&self) -> Duration {
+        Duration::from_secs(self.stats_interval_secs)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs)
+    }
+}
+
+/// Verifica che la cartella che conterrà il file esista
+fn check_parent_dir(name: &str, path: &Path) -> Result<(), String> {
+    if path.as_os_str().is_empty() {
+        return Err(format!("Invalid {}: path is empty", name));
+    }
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => Err(format!(
+            "Invalid {} '{}': directory '{}' does not exist",
+            name,
+            path.display(),
+            dir.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scrive un file di configurazione temporaneo, distinto per ogni test
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ruggine-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn cli_overrides_env_which_overrides_file() {
+        let path = write_config("precedence", "bind_address = \"127.0.0.1:9000\"\nrecent_messages = 5\nmax_history_page = 50\n");
+        // Le variabili d'ambiente sono globali al processo: solo questo test ne imposta
+        std::env::set_var("RUGGINE_RECENT_MESSAGES", "7");
+        std::env::set_var("RUGGINE_MAX_HISTORY_PAGE", "60");
+        let args = ServerArgs::try_parse_from(["server", "--config", path.to_str().unwrap(), "--max-history-page", "70"]).unwrap();
+        std::env::remove_var("RUGGINE_RECENT_MESSAGES");
+        std::env::remove_var("RUGGINE_MAX_HISTORY_PAGE");
+        let config = ServerConfig::from_args(args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.bind_address, "127.0.0.1:9000");
+        assert_eq!(config.recent_messages, 7);
+        assert_eq!(config.max_history_page, 70);
+        assert_eq!(config.heartbeat_secs, ServerConfig::default().heartbeat_secs);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let path = write_config("unknown", "heartbeat_sec = 10\n");
+        let result = ServerConfig::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().contains("heartbeat_sec"));
+
+        let config: ServerConfig = toml::from_str("heartbeat_secs = 10").unwrap();
+        assert_eq!(config.heartbeat_secs, 10);
+        assert_eq!(config.outbound_queue, ServerConfig::default().outbound_queue);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(ServerConfig::default().validate().is_ok());
+
+        let invalid: Vec<fn(&mut ServerConfig)> = vec![
+            |config| config.bind_address = "localhost".to_string(),
+            |config| config.database_path = PathBuf::from("missing-dir/ruggine.db"),
+            |config| config.performance_log = PathBuf::new(),
+            |config| config.recent_messages = 0,
+            |config| config.heartbeat_max_missed = 0,
+            |config| config.stats_interval_secs = 0,
+            |config| config.stats_interval_secs = MAX_STATS_INTERVAL_SECS + 1,
+            |config| config.heartbeat_secs = 0,
+            |config| config.heartbeat_secs = u64::MAX,
+            |config| config.session_ttl_secs = 0,
+            |config| config.session_ttl_secs = MAX_SESSION_TTL_SECS + 1,
+            |config| config.invite_ttl_secs = Some(-1),
+            |config| config.invite_ttl_secs = Some(MAX_INVITE_TTL_SECS + 1),
+            |config| config.tls_cert = Some(PathBuf::from("server.crt")),
+            |config| {
+                config.tls_cert = Some(PathBuf::from("missing.crt"));
+                config.tls_key = Some(PathBuf::from("missing.key"));
+            },
+        ];
+        for (index, change) in invalid.into_iter().enumerate() {
+            let mut config = ServerConfig::default();
+            change(&mut config);
+            assert!(config.validate().is_err(), "case {} should be rejected", index);
+        }
+
+        let config = ServerConfig {
+            invite_ttl_secs: Some(MAX_INVITE_TTL_SECS),
+            session_ttl_secs: MAX_SESSION_TTL_SECS,
+            heartbeat_secs: MAX_HEARTBEAT_SECS,
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+}
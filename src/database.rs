@@ -1,18 +1,53 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use rusqlite::{Connection, Result as SqlResult, params};
+use rusqlite::{Connection, Result as SqlResult, Row, params};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use uuid::Uuid;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use crate::common::*;
 
+/// Prefisso del nome interno dei gruppi che contengono le conversazioni dirette
+const DIRECT_GROUP_PREFIX: &str = "dm:";
+
+/// Lunghezza massima di argomento e descrizione di un gruppo
+const MAX_GROUP_INFO_LENGTH: usize = 300;
+
+/// Lunghezza massima di una reazione, in caratteri: una emoji anche composta
+/// (sequenze con ZWJ, tonalità della pelle, bandiere)
+const MAX_REACTION_LENGTH: usize = 16;
+
+/// Messaggi di cui attach_reactions legge le reazioni con una sola query
+const REACTIONS_BATCH_SIZE: usize = 500;
+
+/// Durata massima di un ban a tempo (10 anni); per periodi più lunghi si usa un ban permanente
+pub const MAX_BAN_DURATION_SECS: i64 = 10 * 365 * 24 * 60 * 60;
+
+/// Durata massima di una sessione (1 anno)
+pub const MAX_SESSION_TTL_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// Validità massima di un invito (1 anno)
+pub const MAX_INVITE_TTL_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// Caratteri minimi di un ID breve di messaggio
+const MIN_MESSAGE_ID_PREFIX: usize = 4;
+
+/// Colonne lette da message_from_row (messages m JOIN users u)
+const MESSAGE_COLUMNS: &str = "m.id, m.content, u.username, m.sent_at, m.edited_at, m.deleted_at IS NOT NULL AS deleted, m.reply_to,
+    (SELECT pu.username FROM messages p JOIN users pu ON pu.id = p.user_id WHERE p.id = m.reply_to) AS reply_username,
+    (SELECT p.content FROM messages p WHERE p.id = m.reply_to AND p.deleted_at IS NULL) AS reply_content";
+
 #[derive(Clone)]
 pub struct Database {
     conn: Arc<Mutex<Connection>>,
 }
 
 impl Database {
-    pub fn new(db_path: &str) -> SqlResult<Self> {
-        let conn = Connection::open(db_path)?;
+    pub fn new<P: AsRef<std::path::Path>>(db_path: P) -> SqlResult<Self> {
+        Self::from_connection(Connection::open(db_path)?)
+    }
+
+    /// Usa una connessione già aperta, creando le tabelle mancanti
+    fn from_connection(conn: Connection) -> SqlResult<Self> {
         let db = Database { 
             conn: Arc::new(Mutex::new(conn)) 
         };
@@ -60,6 +95,32 @@ impl Database {
             [],
         )?;
 
+        // Ruolo dei membri: i database creati prima dei ruoli vengono aggiornati e il creatore diventa owner
+        if !column_exists(&conn, "group_memberships", "role")? {
+            conn.execute_batch(
+                "ALTER TABLE group_memberships ADD COLUMN role TEXT NOT NULL DEFAULT 'member';
+                 UPDATE group_memberships SET role = 'owner'
+                 WHERE user_id = (SELECT creator_id FROM groups WHERE groups.id = group_memberships.group_id);",
+            )?;
+        }
+
+        // Visibilità e descrizione dei gruppi: i gruppi già esistenti restano pubblici
+        if !column_exists(&conn, "groups", "visibility")? {
+            conn.execute_batch(
+                "ALTER TABLE groups ADD COLUMN visibility TEXT NOT NULL DEFAULT 'public';
+                 ALTER TABLE groups ADD COLUMN description TEXT;",
+            )?;
+        }
+
+        if !column_exists(&conn, "groups", "topic")? {
+            conn.execute("ALTER TABLE groups ADD COLUMN topic TEXT", [])?;
+        }
+
+        // Data di archiviazione (NULL = gruppo attivo)
+        if !column_exists(&conn, "groups", "archived_at")? {
+            conn.execute("ALTER TABLE groups ADD COLUMN archived_at TEXT", [])?;
+        }
+
         // Tabella messaggi
         conn.execute(
             "CREATE TABLE IF NOT EXISTS messages (
@@ -74,6 +135,81 @@ impl Database {
             [],
         )?;
 
+        // Modifiche ed eliminazioni: un messaggio eliminato resta come segnaposto senza contenuto
+        if !column_exists(&conn, "messages", "edited_at")? {
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN edited_at TEXT;
+                 ALTER TABLE messages ADD COLUMN deleted_at TEXT;
+                 ALTER TABLE messages ADD COLUMN deleted_by TEXT REFERENCES users(id);",
+            )?;
+        }
+
+        // Risposte: ogni messaggio può citarne un altro dello stesso gruppo
+        if !column_exists(&conn, "messages", "reply_to")? {
+            conn.execute("ALTER TABLE messages ADD COLUMN reply_to TEXT REFERENCES messages(id)", [])?;
+        }
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_reply_to ON messages(reply_to)", [])?;
+
+        // Reazioni ai messaggi: ogni utente può usare più emoji, ma ognuna una sola volta per messaggio
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_reactions (
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY(message_id) REFERENCES messages(id),
+                FOREIGN KEY(user_id) REFERENCES users(id),
+                PRIMARY KEY(message_id, user_id, emoji)
+            )",
+            [],
+        )?;
+
+        // Menzioni @utente: read_at resta NULL finché l'utente non le ha viste
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_mentions (
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                read_at TEXT,
+                FOREIGN KEY(message_id) REFERENCES messages(id),
+                FOREIGN KEY(user_id) REFERENCES users(id),
+                PRIMARY KEY(message_id, user_id)
+            )",
+            [],
+        )?;
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_message_mentions_user ON message_mentions(user_id, read_at)", [])?;
+
+        // Ultimo messaggio letto da ogni utente in ogni gruppo, per contare i non letti.
+        // Alla creazione della tabella i gruppi esistenti valgono come già letti.
+        let group_reads_exists: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'group_reads'",
+            [],
+            |row| row.get(0),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS group_reads (
+                user_id TEXT NOT NULL,
+                group_id TEXT NOT NULL,
+                last_read_message_id TEXT,
+                last_read_at TEXT NOT NULL,
+                FOREIGN KEY(user_id) REFERENCES users(id),
+                FOREIGN KEY(group_id) REFERENCES groups(id),
+                PRIMARY KEY(user_id, group_id)
+            )",
+            [],
+        )?;
+        // Messaggi di un gruppo in ordine di invio: ultimo messaggio e conteggio dei non letti
+        conn.execute("CREATE INDEX IF NOT EXISTS idx_messages_group_sent ON messages(group_id, sent_at, id)", [])?;
+        if !group_reads_exists {
+            conn.execute(
+                "INSERT OR IGNORE INTO group_reads (user_id, group_id, last_read_message_id, last_read_at)
+                 SELECT gm.user_id, gm.group_id, m.id, m.sent_at
+                 FROM group_memberships gm
+                 JOIN messages m ON m.id = (SELECT id FROM messages WHERE group_id = gm.group_id ORDER BY sent_at DESC, id DESC LIMIT 1)",
+                [],
+            )?;
+        }
+
         // Tabella per tracciare chi ha abbandonato un gruppo
         conn.execute(
             "CREATE TABLE IF NOT EXISTS group_departures (
@@ -88,6 +224,100 @@ impl Database {
             [],
         )?;
 
+        // Tabella inviti in attesa di risposta (expires_at in secondi Unix, NULL = non scade)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS group_invites (
+                id TEXT PRIMARY KEY,
+                group_id TEXT NOT NULL,
+                invited_user_id TEXT NOT NULL,
+                inviter_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at INTEGER,
+                FOREIGN KEY(group_id) REFERENCES groups(id),
+                FOREIGN KEY(invited_user_id) REFERENCES users(id),
+                FOREIGN KEY(inviter_id) REFERENCES users(id),
+                UNIQUE(group_id, invited_user_id)
+            )",
+            [],
+        )?;
+
+        // Tabella utenti banditi da un gruppo (expires_at in secondi Unix, NULL = ban permanente)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS group_bans (
+                id TEXT PRIMARY KEY,
+                group_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                banned_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at INTEGER,
+                FOREIGN KEY(group_id) REFERENCES groups(id),
+                FOREIGN KEY(user_id) REFERENCES users(id),
+                FOREIGN KEY(banned_by) REFERENCES users(id),
+                UNIQUE(group_id, user_id)
+            )",
+            [],
+        )?;
+
+        // Conversazioni dirette: i messaggi stanno in un gruppo nascosto, unico per ogni coppia di utenti (user_a < user_b)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS direct_conversations (
+                group_id TEXT PRIMARY KEY,
+                user_a TEXT NOT NULL,
+                user_b TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY(group_id) REFERENCES groups(id),
+                FOREIGN KEY(user_a) REFERENCES users(id),
+                FOREIGN KEY(user_b) REFERENCES users(id),
+                UNIQUE(user_a, user_b)
+            )",
+            [],
+        )?;
+
+        // Tabella sessioni: token opachi con scadenza, per riprendere la sessione dopo una disconnessione
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                token TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at INTEGER NOT NULL,
+                current_group_id TEXT,
+                FOREIGN KEY(user_id) REFERENCES users(id),
+                FOREIGN KEY(current_group_id) REFERENCES groups(id)
+            )",
+            [],
+        )?;
+
+        // Indice full-text dei messaggi, tenuto allineato alla tabella messages tramite trigger
+        let fts_exists: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts'",
+            [],
+            |row| row.get(0),
+        )?;
+        conn.execute(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+                message_id UNINDEXED,
+                content
+            )",
+            [],
+        )?;
+        if fts_exists == 0 {
+            // Indicizza i messaggi già presenti nel database
+            conn.execute("INSERT INTO messages_fts (message_id, content) SELECT id, content FROM messages", [])?;
+        }
+
+        conn.execute_batch(
+            "CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (message_id, content) VALUES (new.id, new.content);
+             END;
+             CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                DELETE FROM messages_fts WHERE message_id = old.id;
+             END;
+             CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                DELETE FROM messages_fts WHERE message_id = old.id;
+                INSERT INTO messages_fts (message_id, content) VALUES (new.id, new.content);
+             END;",
+        )?;
+
         Ok(())
     }
 
@@ -130,6 +360,81 @@ impl Database {
         }
     }
 
+    /// Crea una nuova sessione per l'utente e restituisce il token
+    pub fn create_session(&self, user_id: &str, ttl_secs: i64) -> Result<String, Box<dyn std::error::Error>> {
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let now = Utc::now();
+        let expires_at = expiry_after(now, ttl_secs, MAX_SESSION_TTL_SECS, "Session duration")?;
+
+        let conn = self.conn.lock().unwrap();
+        
+        // Elimina le sessioni scadute
+        conn.execute("DELETE FROM sessions WHERE expires_at < ?1", params![now.timestamp()])?;
+
+        conn.execute(
+            "INSERT INTO sessions (token, user_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![token, user_id, now.to_rfc3339(), expires_at.timestamp()],
+        )?;
+
+        Ok(token)
+    }
+
+    /// Riprende una sessione valida: restituisce l'utente e il gruppo in cui si trovava (se ne è ancora membro).
+    /// La scadenza della sessione viene prolungata.
+    pub fn resume_session(&self, token: &str, ttl_secs: i64) -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+        let now = Utc::now();
+        let expires_at = expiry_after(now, ttl_secs, MAX_SESSION_TTL_SECS, "Session duration")?;
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare("SELECT user_id, current_group_id, expires_at FROM sessions WHERE token = ?1")?;
+        let (user_id, group_id, current_expiry) = stmt.query_row(params![token], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?, row.get::<_, i64>(2)?))
+        }).map_err(|_| "Invalid session token")?;
+
+        if current_expiry < now.timestamp() {
+            conn.execute("DELETE FROM sessions WHERE token = ?1", params![token])?;
+            return Err("Session expired, please log in again".into());
+        }
+
+        conn.execute(
+            "UPDATE sessions SET expires_at = ?1 WHERE token = ?2",
+            params![expires_at.timestamp(), token],
+        )?;
+
+        // Ripristina il gruppo solo se l'utente ne è ancora membro
+        let group_name = match group_id {
+            Some(group_id) => {
+                let mut group_stmt = conn.prepare(
+                    "SELECT g.name 
+                     FROM groups g 
+                     JOIN group_memberships gm ON g.id = gm.group_id 
+                     WHERE g.id = ?1 AND gm.user_id = ?2"
+                )?;
+                group_stmt.query_row(params![group_id, user_id], |row| row.get::<_, String>(0)).ok()
+            }
+            None => None,
+        };
+
+        Ok((user_id, group_name))
+    }
+
+    /// Memorizza il gruppo corrente della sessione (None = home)
+    pub fn update_session_group(&self, token: &str, group_id: Option<&str>) -> SqlResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE sessions SET current_group_id = ?1 WHERE token = ?2",
+            params![group_id, token],
+        )?;
+        Ok(())
+    }
+
+    /// Revoca una sessione (logout)
+    pub fn revoke_session(&self, token: &str) -> SqlResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM sessions WHERE token = ?1", params![token])?;
+        Ok(())
+    }
+
     fn user_exists(&self, username: &str) -> SqlResult<bool> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare("SELECT COUNT(*) FROM users WHERE username = ?1")?;
@@ -137,7 +442,12 @@ impl Database {
         Ok(count > 0)
     }
 
-    pub fn create_group(&self, name: &str, creator_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn create_group(&self, name: &str, creator_id: &str, visibility: GroupVisibility) -> Result<(), Box<dyn std::error::Error>> {
+        // "@utente" indica una conversazione diretta, "dm:" è riservato ai loro gruppi interni
+        if name.starts_with('@') || name.starts_with(DIRECT_GROUP_PREFIX) {
+            return Err(format!("Group names cannot start with '@' or '{}'", DIRECT_GROUP_PREFIX).into());
+        }
+
         let group_id = Uuid::new_v4().to_string();
         let created_at = Utc::now().to_rfc3339();
 
@@ -145,8 +455,8 @@ impl Database {
         
         // Crea il gruppo
         conn.execute(
-            "INSERT INTO groups (id, name, creator_id, created_at) VALUES (?1, ?2, ?3, ?4)",
-            params![group_id, name, creator_id, created_at],
+            "INSERT INTO groups (id, name, creator_id, created_at, visibility) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![group_id, name, creator_id, created_at, visibility.as_str()],
         )?;
 
         // Aggiunge il creatore al gruppo
@@ -154,30 +464,103 @@ impl Database {
         let joined_at = Utc::now().to_rfc3339();
         
         conn.execute(
-            "INSERT INTO group_memberships (id, group_id, user_id, joined_at) VALUES (?1, ?2, ?3, ?4)",
-            params![membership_id, group_id, creator_id, joined_at],
+            "INSERT INTO group_memberships (id, group_id, user_id, joined_at, role) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![membership_id, group_id, creator_id, joined_at, GroupRole::Owner.as_str()],
         )?;
 
         Ok(())
     }
 
+    pub fn get_group(&self, group_name: &str) -> Result<Group, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        find_group(&conn, group_name)
+    }
+
+    /// Rinomina il gruppo: l'ID non cambia, quindi appartenenze, messaggi e sessioni restano validi
+    pub fn rename_group(&self, group_name: &str, new_name: &str, renamed_by: &str) -> Result<Group, Box<dyn std::error::Error>> {
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            return Err("The new group name is empty".into());
+        }
+        if new_name.starts_with('@') || new_name.starts_with(DIRECT_GROUP_PREFIX) {
+            return Err(format!("Group names cannot start with '@' or '{}'", DIRECT_GROUP_PREFIX).into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let group = find_group(&conn, group_name)?;
+        require_permission(&conn, &group.id, renamed_by, GroupAction::Rename)?;
+
+        let mut check_stmt = conn.prepare("SELECT COUNT(*) FROM groups WHERE name = ?1 AND id != ?2")?;
+        let taken: i64 = check_stmt.query_row(params![new_name, group.id], |row| row.get(0))?;
+        if taken > 0 {
+            return Err(format!("A group named '{}' already exists", new_name).into());
+        }
+
+        conn.execute("UPDATE groups SET name = ?1 WHERE id = ?2", params![new_name, group.id])?;
+        find_group(&conn, new_name)
+    }
+
+    /// Imposta l'argomento del gruppo (None o testo vuoto lo rimuove)
+    pub fn set_group_topic(&self, group_name: &str, topic: Option<&str>, changed_by: &str) -> Result<Group, Box<dyn std::error::Error>> {
+        self.update_group_info(group_name, "topic", topic, changed_by)
+    }
+
+    /// Imposta la descrizione del gruppo (None o testo vuoto la rimuove)
+    pub fn set_group_description(&self, group_name: &str, description: Option<&str>, changed_by: &str) -> Result<Group, Box<dyn std::error::Error>> {
+        self.update_group_info(group_name, "description", description, changed_by)
+    }
+
+    fn update_group_info(&self, group_name: &str, column: &str, value: Option<&str>, changed_by: &str) -> Result<Group, Box<dyn std::error::Error>> {
+        let value = value.map(str::trim).filter(|value| !value.is_empty());
+        if value.is_some_and(|value| value.chars().count() > MAX_GROUP_INFO_LENGTH) {
+            return Err(format!("The {} can be at most {} characters long", column, MAX_GROUP_INFO_LENGTH).into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let group = find_group(&conn, group_name)?;
+        require_permission(&conn, &group.id, changed_by, GroupAction::EditInfo)?;
+
+        conn.execute(&format!("UPDATE groups SET {} = ?1 WHERE id = ?2", column), params![value, group.id])?;
+        find_group(&conn, group_name)
+    }
+
+    /// Gruppi attivi dell'utente (senza conversazioni dirette e gruppi archiviati)
     pub fn get_user_groups(&self, user_id: &str) -> Result<Vec<Group>, Box<dyn std::error::Error>> {
+        self.user_groups(user_id, false)
+    }
+
+    /// Gruppi archiviati di cui l'utente è ancora membro
+    pub fn get_archived_groups(&self, user_id: &str) -> Result<Vec<Group>, Box<dyn std::error::Error>> {
+        self.user_groups(user_id, true)
+    }
+
+    fn user_groups(&self, user_id: &str, archived: bool) -> Result<Vec<Group>, Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
             
         let mut stmt = conn.prepare(
-            "SELECT g.id, g.name, g.creator_id, g.created_at 
+            "SELECT g.id, g.name, g.creator_id, g.created_at, g.visibility, g.description, g.topic, g.archived_at IS NOT NULL,
+                    (SELECT COUNT(*) FROM messages m
+                     WHERE m.group_id = g.id AND m.user_id != ?1 AND m.deleted_at IS NULL
+                       AND m.sent_at > COALESCE((SELECT last_read_at FROM group_reads WHERE user_id = ?1 AND group_id = g.id), '')) AS unread
              FROM groups g 
              JOIN group_memberships gm ON g.id = gm.group_id 
-             WHERE gm.user_id = ?1"
+             WHERE gm.user_id = ?1
+               AND (g.archived_at IS NOT NULL) = ?2
+               AND g.id NOT IN (SELECT group_id FROM direct_conversations)"
         )?;
 
-        let group_iter = stmt.query_map(params![user_id], |row| {
+        let group_iter = stmt.query_map(params![user_id, archived], |row| {
             Ok(Group {
                 id: row.get::<_, String>(0)?,
                 name: row.get::<_, String>(1)?,
                 creator_id: row.get::<_, String>(2)?,
                 created_at: row.get::<_, String>(3)?,
                 members: Vec::new(), // Popoleremo dopo se necessario
+                visibility: GroupVisibility::from_db(&row.get::<_, String>(4)?),
+                description: row.get::<_, Option<String>>(5)?,
+                topic: row.get::<_, Option<String>>(6)?,
+                archived: row.get::<_, bool>(7)?,
+                unread_count: row.get::<_, u32>(8)?,
             })
         })?;
 
@@ -189,12 +572,76 @@ impl Database {
         Ok(groups)
     }
 
+    /// Archivia il gruppo (o lo riattiva): un gruppo archiviato resta leggibile dai membri ma non accetta
+    /// nuovi messaggi, nuovi membri o inviti. Solo l'owner può farlo; restituisce il gruppo e gli ID dei membri.
+    pub fn set_group_archived(&self, group_name: &str, archived: bool, changed_by: &str) -> Result<(Group, Vec<String>), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let group = find_group(&conn, group_name)?;
+        if is_direct(&conn, &group.id)? {
+            return Err("Group not found".into());
+        }
+        require_permission(&conn, &group.id, changed_by, GroupAction::Archive)?;
+
+        if group.archived == archived {
+            let state = if archived { "already archived" } else { "not archived" };
+            return Err(format!("Group '{}' is {}", group_name, state).into());
+        }
+
+        if archived {
+            conn.execute(
+                "UPDATE groups SET archived_at = ?1 WHERE id = ?2",
+                params![Utc::now().to_rfc3339(), group.id],
+            )?;
+            // Gli inviti in attesa non possono più essere accettati e chi riprende la sessione torna nella home
+            conn.execute("DELETE FROM group_invites WHERE group_id = ?1", params![group.id])?;
+            conn.execute("UPDATE sessions SET current_group_id = NULL WHERE current_group_id = ?1", params![group.id])?;
+        } else {
+            conn.execute("UPDATE groups SET archived_at = NULL WHERE id = ?1", params![group.id])?;
+        }
+
+        Ok((find_group(&conn, group_name)?, member_ids(&conn, &group.id)?))
+    }
+
+    /// Elimina definitivamente il gruppo con membri, partenze, inviti, ban e messaggi.
+    /// Solo l'owner può farlo; restituisce il gruppo eliminato e gli ID di chi ne era membro.
+    pub fn delete_group(&self, group_name: &str, deleted_by: &str) -> Result<(Group, Vec<String>), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let group = find_group(&conn, group_name)?;
+        if is_direct(&conn, &group.id)? {
+            return Err("Group not found".into());
+        }
+        require_permission(&conn, &group.id, deleted_by, GroupAction::Delete)?;
+
+        let member_ids = member_ids(&conn, &group.id)?;
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM message_reactions WHERE message_id IN (SELECT id FROM messages WHERE group_id = ?1)",
+            params![group.id],
+        )?;
+        tx.execute(
+            "DELETE FROM message_mentions WHERE message_id IN (SELECT id FROM messages WHERE group_id = ?1)",
+            params![group.id],
+        )?;
+        tx.execute("DELETE FROM messages WHERE group_id = ?1", params![group.id])?;
+        tx.execute("DELETE FROM group_memberships WHERE group_id = ?1", params![group.id])?;
+        tx.execute("DELETE FROM group_departures WHERE group_id = ?1", params![group.id])?;
+        tx.execute("DELETE FROM group_reads WHERE group_id = ?1", params![group.id])?;
+        tx.execute("DELETE FROM group_invites WHERE group_id = ?1", params![group.id])?;
+        tx.execute("DELETE FROM group_bans WHERE group_id = ?1", params![group.id])?;
+        tx.execute("UPDATE sessions SET current_group_id = NULL WHERE current_group_id = ?1", params![group.id])?;
+        tx.execute("DELETE FROM groups WHERE id = ?1", params![group.id])?;
+        tx.commit()?;
+
+        Ok((group, member_ids))
+    }
+
     pub fn get_all_users(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare("SELECT username FROM users ORDER BY username")?;
         
         let user_iter = stmt.query_map([], |row| {
-            Ok(row.get::<_, String>(0)?)
+            row.get::<_, String>(0)
         })?;
 
         let mut users = Vec::new();
@@ -214,7 +661,8 @@ impl Database {
 
     pub fn get_group_count(&self) -> SqlResult<u32> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT COUNT(*) FROM groups")?;
+        // Le conversazioni dirette non contano come gruppi
+        let mut stmt = conn.prepare("SELECT COUNT(*) FROM groups WHERE id NOT IN (SELECT group_id FROM direct_conversations)")?;
         let count: i64 = stmt.query_row([], |row| row.get(0))?;
         Ok(count as u32)
     }
@@ -230,9 +678,13 @@ impl Database {
         let conn = self.conn.lock().unwrap();
                 
         // Trova l'ID del gruppo
-        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
-        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+        let mut stmt = conn.prepare("SELECT id, visibility, archived_at IS NOT NULL FROM groups WHERE name = ?1")?;
+        let (group_id, visibility, archived): (String, String, bool) = stmt
+            .query_row(params![group_name], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
             .map_err(|_| "Group not found")?;
+        if is_direct(&conn, &group_id)? {
+            return Err("Group not found".into());
+        }
 
         // Verifica se l'utente è già nel gruppo
         let mut check_stmt = conn.prepare("SELECT COUNT(*) FROM group_memberships WHERE group_id = ?1 AND user_id = ?2")?;
@@ -243,15 +695,41 @@ impl Database {
             return Ok(());
         }
 
-        // Verifica se l'utente ha abbandonato questo gruppo in precedenza
-        let mut departure_stmt = conn.prepare("SELECT COUNT(*) FROM group_departures WHERE group_id = ?1 AND user_id = ?2")?;
-        let departure_count: i64 = departure_stmt.query_row(params![group_id, user_id], |row| row.get(0))?;
-                
-        if departure_count > 0 {
-            return Err("You cannot rejoin a group you have left. You need to be invited by another member.".into());
+        if archived {
+            return Err("This group is archived: it does not accept new members".into());
+        }
+
+        if let Some(expires_at) = active_ban(&conn, &group_id, user_id)? {
+            return Err(format!("You are banned from this group {}", describe_ban(expires_at)).into());
+        }
+
+        // Un invito ancora valido vale come accettazione e viene consumato
+        let invited = conn.execute(
+            "DELETE FROM group_invites
+             WHERE group_id = ?1 AND invited_user_id = ?2 AND (expires_at IS NULL OR expires_at >= ?3)",
+            params![group_id, user_id, Utc::now().timestamp()],
+        )? > 0;
+
+        if invited {
+            conn.execute(
+                "DELETE FROM group_departures WHERE group_id = ?1 AND user_id = ?2",
+                params![group_id, user_id],
+            )?;
+        } else {
+            if GroupVisibility::from_db(&visibility) == GroupVisibility::Private {
+                return Err("This group is private: you need an invitation to join".into());
+            }
+
+            // Verifica se l'utente ha abbandonato questo gruppo in precedenza
+            let mut departure_stmt = conn.prepare("SELECT COUNT(*) FROM group_departures WHERE group_id = ?1 AND user_id = ?2")?;
+            let departure_count: i64 = departure_stmt.query_row(params![group_id, user_id], |row| row.get(0))?;
+
+            if departure_count > 0 {
+                return Err("You cannot rejoin a group you have left. You need to be invited by another member.".into());
+            }
         }
 
-        // Aggiunge l'utente al gruppo (solo se non era già membro e non ha mai abbandonato)
+        // Aggiunge l'utente al gruppo (solo se non era già membro e non ha mai abbandonato, o se è stato invitato)
         let membership_id = Uuid::new_v4().to_string();
         let joined_at = Utc::now().to_rfc3339();
         
@@ -263,7 +741,9 @@ impl Database {
         Ok(())
     }
 
-    pub fn invite_user_to_group(&self, group_name: &str, username: &str, inviter_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    /// Crea un invito in attesa per l'utente indicato (un nuovo invito sostituisce quello precedente).
+    /// L'utente entra nel gruppo solo quando accetta l'invito.
+    pub fn invite_user_to_group(&self, group_name: &str, username: &str, inviter_id: &str, ttl_secs: Option<i64>) -> Result<GroupInvite, Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
         
         // Trova l'ID del gruppo
@@ -271,13 +751,15 @@ impl Database {
         let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
             .map_err(|_| "Group not found")?;
 
-        // Verifica che l'invitante sia nel gruppo
-        let mut check_inviter = conn.prepare("SELECT COUNT(*) FROM group_memberships WHERE group_id = ?1 AND user_id = ?2")?;
-        let inviter_count: i64 = check_inviter.query_row(params![group_id, inviter_id], |row| row.get(0))?;
-        
-        if inviter_count == 0 {
-            return Err("You are not a member of this group".into());
+        if is_direct(&conn, &group_id)? {
+            return Err("Direct conversations are between two users only".into());
         }
+        if is_archived(&conn, &group_id)? {
+            return Err("This group is archived: it does not accept new members".into());
+        }
+
+        // Verifica che l'invitante possa invitare in questo gruppo
+        require_permission(&conn, &group_id, inviter_id, GroupAction::Invite)?;
 
         // Trova l'ID dell'utente da invitare
         let mut user_stmt = conn.prepare("SELECT id FROM users WHERE username = ?1")?;
@@ -292,6 +774,102 @@ impl Database {
             return Err("User is already in the group".into());
         }
 
+        if let Some(expires_at) = active_ban(&conn, &group_id, &user_id)? {
+            return Err(format!("'{}' is banned from this group {}", username, describe_ban(expires_at)).into());
+        }
+
+        let inviter_username: String = conn.query_row(
+            "SELECT username FROM users WHERE id = ?1",
+            params![inviter_id],
+            |row| row.get(0),
+        )?;
+
+        let mut invite = GroupInvite::new(group_id, group_name.to_string(), user_id, inviter_id.to_string(), inviter_username);
+        invite.expires_at = match ttl_secs {
+            Some(ttl) => Some(expiry_after(invite.timestamp, ttl, MAX_INVITE_TTL_SECS, "Invitation validity")?),
+            None => None,
+        };
+
+        conn.execute(
+            "INSERT OR REPLACE INTO group_invites (id, group_id, invited_user_id, inviter_id, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                invite.id,
+                invite.group_id,
+                invite.invited_user,
+                invite.inviter,
+                invite.timestamp.to_rfc3339(),
+                invite.expires_at.map(|expires_at| expires_at.timestamp()),
+            ],
+        )?;
+
+        Ok(invite)
+    }
+
+    /// Restituisce gli inviti ancora validi ricevuti dall'utente, eliminando quelli scaduti
+    pub fn get_pending_invites(&self, user_id: &str) -> Result<Vec<GroupInvite>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "DELETE FROM group_invites WHERE invited_user_id = ?1 AND expires_at IS NOT NULL AND expires_at < ?2",
+            params![user_id, Utc::now().timestamp()],
+        )?;
+
+        let mut stmt = conn.prepare(
+            "SELECT i.id, i.group_id, g.name, i.inviter_id, u.username, i.created_at, i.expires_at
+             FROM group_invites i
+             JOIN groups g ON g.id = i.group_id
+             JOIN users u ON u.id = i.inviter_id
+             WHERE i.invited_user_id = ?1
+             ORDER BY i.created_at"
+        )?;
+
+        let invite_iter = stmt.query_map(params![user_id], |row| {
+            let created_at: String = row.get(5)?;
+            Ok(GroupInvite {
+                id: row.get::<_, String>(0)?,
+                group_id: row.get::<_, String>(1)?,
+                group_name: row.get::<_, String>(2)?,
+                invited_user: user_id.to_string(),
+                inviter: row.get::<_, String>(3)?,
+                inviter_username: row.get::<_, String>(4)?,
+                timestamp: chrono::DateTime::parse_from_rfc3339(&created_at)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                expires_at: row.get::<_, Option<i64>>(6)?.and_then(|secs| chrono::DateTime::from_timestamp(secs, 0)),
+            })
+        })?;
+
+        let mut invites = Vec::new();
+        for invite in invite_iter {
+            invites.push(invite?);
+        }
+
+        Ok(invites)
+    }
+
+    /// Accetta l'invito a un gruppo: l'invito viene consumato e l'utente diventa membro
+    pub fn accept_invite(&self, group_name: &str, user_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        let mut invite_stmt = conn.prepare("SELECT expires_at FROM group_invites WHERE group_id = ?1 AND invited_user_id = ?2")?;
+        let expires_at: Option<i64> = invite_stmt.query_row(params![group_id, user_id], |row| row.get(0))
+            .map_err(|_| format!("No pending invitation for group '{}'", group_name))?;
+
+        conn.execute(
+            "DELETE FROM group_invites WHERE group_id = ?1 AND invited_user_id = ?2",
+            params![group_id, user_id],
+        )?;
+
+        if expires_at.is_some_and(|expires_at| expires_at < Utc::now().timestamp()) {
+            return Err(format!("The invitation to group '{}' has expired", group_name).into());
+        }
+
         // Se l'utente aveva abbandonato il gruppo in precedenza, rimuovi il record di partenza
         conn.execute(
             "DELETE FROM group_departures WHERE group_id = ?1 AND user_id = ?2",
@@ -303,13 +881,29 @@ impl Database {
         let joined_at = Utc::now().to_rfc3339();
         
         conn.execute(
-            "INSERT INTO group_memberships (id, group_id, user_id, joined_at) VALUES (?1, ?2, ?3, ?4)",
+            "INSERT OR IGNORE INTO group_memberships (id, group_id, user_id, joined_at) VALUES (?1, ?2, ?3, ?4)",
             params![membership_id, group_id, user_id, joined_at],
         )?;
 
         Ok(())
     }
 
+    /// Rifiuta l'invito a un gruppo
+    pub fn decline_invite(&self, group_name: &str, user_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let deleted = conn.execute(
+            "DELETE FROM group_invites
+             WHERE invited_user_id = ?1 AND group_id = (SELECT id FROM groups WHERE name = ?2)",
+            params![user_id, group_name],
+        )?;
+
+        if deleted == 0 {
+            return Err(format!("No pending invitation for group '{}'", group_name).into());
+        }
+        Ok(())
+    }
+
     pub fn leave_group(&self, group_name: &str, user_id: &str) -> Result<(), Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
                 
@@ -318,39 +912,149 @@ impl Database {
         let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
             .map_err(|_| "Group not found")?;
 
+        if is_direct(&conn, &group_id)? {
+            return Err("You cannot leave a direct conversation".into());
+        }
+
+        let role = member_role(&conn, &group_id, user_id)?
+            .ok_or("You are not a member of this group")?;
+
         // Rimuove l'utente dal gruppo
-        let rows_affected = conn.execute(
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
             "DELETE FROM group_memberships WHERE group_id = ?1 AND user_id = ?2",
             params![group_id, user_id],
         )?;
 
-        if rows_affected == 0 {
-            return Err("You are not a member of this group".into());
+        // Se esce l'owner, il gruppo passa all'admin (o in mancanza al membro) presente da più tempo
+        if role == GroupRole::Owner {
+            tx.execute(
+                "UPDATE group_memberships SET role = 'owner'
+                 WHERE id = (SELECT id FROM group_memberships WHERE group_id = ?1
+                             ORDER BY role = 'admin' DESC, joined_at LIMIT 1)",
+                params![group_id],
+            )?;
         }
 
         // Registra la partenza nella tabella group_departures
-        let departure_id = Uuid::new_v4().to_string();
-        let left_at = Utc::now().to_rfc3339();
-        
-        // Usa INSERT OR REPLACE per evitare errori se l'utente ha già abbandonato questo gruppo in passato
-        conn.execute(
-            "INSERT OR REPLACE INTO group_departures (id, group_id, user_id, left_at) VALUES (?1, ?2, ?3, ?4)",
-            params![departure_id, group_id, user_id, left_at],
-        )?;
+        record_departure(&tx, &group_id, user_id)?;
+        tx.commit()?;
 
         Ok(())
     }
 
-    pub fn get_group_members(&self, group_name: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    /// Espelle un membro dal gruppo: per rientrare dovrà essere invitato di nuovo.
+    /// Restituisce l'ID dell'utente espulso.
+    pub fn kick_member(&self, group_name: &str, username: &str, kicked_by: &str) -> Result<String, Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
-        
+
         // Trova l'ID del gruppo
         let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
         let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
             .map_err(|_| "Group not found")?;
 
-        // Ottiene i membri del gruppo
-        let mut members_stmt = conn.prepare(
+        let (user_id, target_role) = moderation_target(&conn, &group_id, username, kicked_by)?;
+        if target_role.is_none() {
+            return Err(format!("'{}' is not a member of this group", username).into());
+        }
+
+        conn.execute(
+            "DELETE FROM group_memberships WHERE group_id = ?1 AND user_id = ?2",
+            params![group_id, user_id],
+        )?;
+        record_departure(&conn, &group_id, &user_id)?;
+
+        Ok(user_id)
+    }
+
+    /// Bandisce un utente dal gruppo (per ttl_secs secondi, o per sempre se None).
+    /// Se è membro viene espulso e i suoi inviti in sospeso vengono annullati.
+    pub fn ban_member(&self, group_name: &str, username: &str, banned_by: &str, ttl_secs: Option<i64>) -> Result<GroupBan, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        let now = Utc::now();
+        let expires_at = match ttl_secs {
+            Some(ttl) => Some(ban_expiry(now, ttl)?),
+            None => None,
+        };
+
+        let (user_id, target_role) = moderation_target(&conn, &group_id, username, banned_by)?;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO group_bans (id, group_id, user_id, banned_by, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                Uuid::new_v4().to_string(),
+                group_id,
+                user_id,
+                banned_by,
+                now.to_rfc3339(),
+                expires_at.map(|expires_at| expires_at.timestamp()),
+            ],
+        )?;
+
+        tx.execute(
+            "DELETE FROM group_invites WHERE group_id = ?1 AND invited_user_id = ?2",
+            params![group_id, user_id],
+        )?;
+        if target_role.is_some() {
+            tx.execute(
+                "DELETE FROM group_memberships WHERE group_id = ?1 AND user_id = ?2",
+                params![group_id, user_id],
+            )?;
+            record_departure(&tx, &group_id, &user_id)?;
+        }
+        tx.commit()?;
+
+        Ok(GroupBan {
+            group_id,
+            user_id,
+            banned_by: banned_by.to_string(),
+            expires_at,
+        })
+    }
+
+    /// Revoca il ban di un utente; per rientrare nel gruppo dovrà comunque essere invitato
+    pub fn unban_member(&self, group_name: &str, username: &str, unbanned_by: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        require_permission(&conn, &group_id, unbanned_by, GroupAction::Kick)?;
+
+        let mut user_stmt = conn.prepare("SELECT id FROM users WHERE username = ?1")?;
+        let user_id: String = user_stmt.query_row(params![username], |row| row.get(0))
+            .map_err(|_| "User not found")?;
+
+        if active_ban(&conn, &group_id, &user_id)?.is_none() {
+            return Err(format!("'{}' is not banned from this group", username).into());
+        }
+        conn.execute(
+            "DELETE FROM group_bans WHERE group_id = ?1 AND user_id = ?2",
+            params![group_id, user_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_group_members(&self, group_name: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        // Ottiene i membri del gruppo
+        let mut members_stmt = conn.prepare(
             "SELECT u.username 
              FROM users u 
              JOIN group_memberships gm ON u.id = gm.user_id 
@@ -359,7 +1063,7 @@ impl Database {
         )?;
 
         let member_iter = members_stmt.query_map(params![group_id], |row| {
-            Ok(row.get::<_, String>(0)?)
+            row.get::<_, String>(0)
         })?;
 
         let mut members = Vec::new();
@@ -370,7 +1074,132 @@ impl Database {
         Ok(members)
     }
 
-    pub fn send_message(&self, group_name: &str, user_id: &str, content: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    /// Gruppi pubblici con numero di membri e descrizione, ordinati per numero di membri
+    pub fn get_public_groups(&self, user_id: &str) -> Result<Vec<GroupSummary>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT g.name, g.description,
+                    (SELECT COUNT(*) FROM group_memberships WHERE group_id = g.id) AS member_count,
+                    EXISTS(SELECT 1 FROM group_memberships WHERE group_id = g.id AND user_id = ?1)
+             FROM groups g
+             WHERE g.visibility = 'public'
+               AND g.archived_at IS NULL
+               AND g.id NOT IN (SELECT group_id FROM direct_conversations)
+             ORDER BY member_count DESC, g.name"
+        )?;
+
+        let group_iter = stmt.query_map(params![user_id], |row| {
+            Ok(GroupSummary {
+                name: row.get::<_, String>(0)?,
+                description: row.get::<_, Option<String>>(1)?,
+                member_count: row.get::<_, u32>(2)?,
+                is_member: row.get::<_, bool>(3)?,
+            })
+        })?;
+
+        let mut groups = Vec::new();
+        for group in group_iter {
+            groups.push(group?);
+        }
+
+        Ok(groups)
+    }
+
+    /// Rende il gruppo pubblico o privato (serve almeno il ruolo di admin)
+    pub fn set_group_visibility(&self, group_name: &str, visibility: GroupVisibility, changed_by: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        require_permission(&conn, &group_id, changed_by, GroupAction::ChangeVisibility)?;
+
+        conn.execute(
+            "UPDATE groups SET visibility = ?1 WHERE id = ?2",
+            params![visibility.as_str(), group_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Membri del gruppo con il loro ruolo: prima l'owner, poi gli admin, poi gli altri in ordine alfabetico.
+    /// I membri dei gruppi privati (e delle conversazioni dirette) sono visibili solo a chi ne fa parte.
+    pub fn get_group_members_with_roles(&self, group_name: &str, requested_by: &str) -> Result<Vec<GroupMember>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let group = find_group(&conn, group_name)?;
+        let group_id = group.id;
+
+        let listed_publicly = group.visibility == GroupVisibility::Public && !is_direct(&conn, &group_id)?;
+        if !listed_publicly && member_role(&conn, &group_id, requested_by)?.is_none() {
+            return Err("You are not a member of this group".into());
+        }
+
+        let mut members_stmt = conn.prepare(
+            "SELECT u.username, gm.role
+             FROM users u
+             JOIN group_memberships gm ON u.id = gm.user_id
+             WHERE gm.group_id = ?1
+             ORDER BY CASE gm.role WHEN 'owner' THEN 0 WHEN 'admin' THEN 1 ELSE 2 END, u.username"
+        )?;
+
+        let member_iter = members_stmt.query_map(params![group_id], |row| {
+            Ok(GroupMember {
+                username: row.get::<_, String>(0)?,
+                role: GroupRole::from_db(&row.get::<_, String>(1)?),
+            })
+        })?;
+
+        let mut members = Vec::new();
+        for member in member_iter {
+            members.push(member?);
+        }
+
+        Ok(members)
+    }
+
+    /// Cambia il ruolo di un membro tra admin e member (solo l'owner può farlo).
+    /// Restituisce l'ID dell'utente modificato.
+    pub fn set_member_role(&self, group_name: &str, username: &str, role: GroupRole, changed_by: &str) -> Result<String, Box<dyn std::error::Error>> {
+        if role == GroupRole::Owner {
+            return Err("A group can only have one owner".into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        require_permission(&conn, &group_id, changed_by, GroupAction::ChangeRoles)?;
+
+        // Trova l'ID dell'utente da modificare
+        let mut user_stmt = conn.prepare("SELECT id FROM users WHERE username = ?1")?;
+        let user_id: String = user_stmt.query_row(params![username], |row| row.get(0))
+            .map_err(|_| "User not found")?;
+
+        match member_role(&conn, &group_id, &user_id)? {
+            None => return Err(format!("'{}' is not a member of this group", username).into()),
+            Some(GroupRole::Owner) => return Err("The owner's role cannot be changed".into()),
+            Some(current) if current == role => {
+                return Err(format!("'{}' already has the {} role", username, role.as_str()).into());
+            }
+            Some(_) => {}
+        }
+
+        conn.execute(
+            "UPDATE group_memberships SET role = ?1 WHERE group_id = ?2 AND user_id = ?3",
+            params![role.as_str(), group_id, user_id],
+        )?;
+
+        Ok(user_id)
+    }
+
+    /// Salva un messaggio; reply_to (ID o ID breve) indica il messaggio dello stesso gruppo a cui risponde
+    pub fn send_message(&self, group_name: &str, user_id: &str, content: &str, reply_to: Option<&str>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
         
         // Trova l'ID del gruppo
@@ -386,13 +1215,31 @@ impl Database {
             return Err("You are not a member of this group".into());
         }
 
+        if is_archived(&conn, &group_id)? {
+            return Err("This group is archived and read-only".into());
+        }
+
+        let reply_to = match reply_to {
+            Some(reply_to) => {
+                let target = message_target(&conn, reply_to, user_id)?;
+                if target.group.id != group_id {
+                    return Err("You can only reply to messages of this group".into());
+                }
+                if target.deleted {
+                    return Err("You cannot reply to a deleted message".into());
+                }
+                Some(target.id)
+            }
+            None => None,
+        };
+
         // Crea il messaggio
         let message_id = Uuid::new_v4().to_string();
         let sent_at = Utc::now().to_rfc3339();
         
         conn.execute(
-            "INSERT INTO messages (id, group_id, user_id, content, sent_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![message_id, group_id, user_id, content, sent_at],
+            "INSERT INTO messages (id, group_id, user_id, content, sent_at, reply_to) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![message_id, group_id, user_id, content, sent_at, reply_to],
         )?;
 
         Ok(vec![message_id, group_id, user_id.to_string(), content.to_string(), sent_at])
@@ -407,34 +1254,481 @@ impl Database {
             .map_err(|_| "Group not found")?;
 
         // Ottiene i messaggi recenti ordinati per timestamp (più recenti per primi)
-        let mut messages_stmt = conn.prepare(
-            "SELECT m.id, m.content, u.username, m.sent_at 
+        let mut messages_stmt = conn.prepare(&format!(
+            "SELECT {} 
              FROM messages m 
              JOIN users u ON m.user_id = u.id 
              WHERE m.group_id = ?1 
              ORDER BY m.sent_at DESC 
-             LIMIT ?2"
-        )?;
+             LIMIT ?2",
+            MESSAGE_COLUMNS
+        ))?;
 
-        let message_iter = messages_stmt.query_map(params![group_id, limit], |row| {
-            Ok(ChatMessage {
-                id: row.get::<_, String>(0)?,
-                content: row.get::<_, String>(1)?,
-                username: row.get::<_, String>(2)?,
-                timestamp: row.get::<_, String>(3)?,
-            })
-        })?;
+        let message_iter = messages_stmt.query_map(params![group_id, limit], message_from_row)?;
 
         let mut messages = Vec::new();
         for message in message_iter {
             messages.push(message?);
         }
+        attach_reactions(&conn, &mut messages)?;
 
         // Inverti l'ordine per avere i messaggi più vecchi per primi
         messages.reverse();
         Ok(messages)
     }
 
+    /// Restituisce una pagina di messaggi in ordine cronologico:
+    /// quelli più vecchi del messaggio `before` (o gli ultimi se `None`) oppure, se indicato,
+    /// quelli più recenti del messaggio `after`. Il booleano indica se esistono altri messaggi oltre la pagina.
+    pub fn get_message_history(&self, group_name: &str, user_id: &str, before: Option<&str>, after: Option<&str>, limit: u32) -> Result<(Vec<ChatMessage>, bool), Box<dyn std::error::Error>> {
+        if before.is_some() && after.is_some() {
+            return Err("Use either 'before' or 'after', not both".into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        
+        // Trova l'ID del gruppo
+        let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
+        let group_id: String = stmt.query_row(params![group_name], |row| row.get(0))
+            .map_err(|_| "Group not found")?;
+
+        // Verifica se l'utente è nel gruppo
+        let mut check_stmt = conn.prepare("SELECT COUNT(*) FROM group_memberships WHERE group_id = ?1 AND user_id = ?2")?;
+        let count: i64 = check_stmt.query_row(params![group_id, user_id], |row| row.get(0))?;
+        
+        if count == 0 {
+            return Err("You are not a member of this group".into());
+        }
+
+        // Il cursore è la coppia (sent_at, id) del messaggio di riferimento
+        let (cursor_sent_at, cursor_id) = match before.or(after) {
+            Some(message_id) => {
+                let mut cursor_stmt = conn.prepare("SELECT sent_at, id FROM messages WHERE id = ?1 AND group_id = ?2")?;
+                cursor_stmt.query_row(params![message_id, group_id], |row| {
+                    Ok((Some(row.get::<_, String>(0)?), Some(row.get::<_, String>(1)?)))
+                }).map_err(|_| "Message not found in this group")?
+            }
+            None => (None, None),
+        };
+
+        // Chiede un messaggio in più per sapere se esistono altre pagine
+        let query = if after.is_some() {
+            format!(
+                "SELECT {} 
+                 FROM messages m 
+                 JOIN users u ON m.user_id = u.id 
+                 WHERE m.group_id = ?1 
+                   AND (m.sent_at > ?2 OR (m.sent_at = ?2 AND m.id > ?3)) 
+                 ORDER BY m.sent_at ASC, m.id ASC 
+                 LIMIT ?4",
+                MESSAGE_COLUMNS
+            )
+        } else {
+            format!(
+                "SELECT {} 
+                 FROM messages m 
+                 JOIN users u ON m.user_id = u.id 
+                 WHERE m.group_id = ?1 
+                   AND (?2 IS NULL OR m.sent_at < ?2 OR (m.sent_at = ?2 AND m.id < ?3)) 
+                 ORDER BY m.sent_at DESC, m.id DESC 
+                 LIMIT ?4",
+                MESSAGE_COLUMNS
+            )
+        };
+        let mut messages_stmt = conn.prepare(&query)?;
+
+        let message_iter = messages_stmt.query_map(params![group_id, cursor_sent_at, cursor_id, limit + 1], message_from_row)?;
+
+        let mut messages = Vec::new();
+        for message in message_iter {
+            messages.push(message?);
+        }
+
+        let has_more = messages.len() > limit as usize;
+        messages.truncate(limit as usize);
+        attach_reactions(&conn, &mut messages)?;
+
+        // Inverti l'ordine per avere i messaggi più vecchi per primi
+        if after.is_none() {
+            messages.reverse();
+        }
+        Ok((messages, has_more))
+    }
+
+    /// Cerca i messaggi che contengono tutti i termini indicati, solo nei gruppi di cui l'utente è membro
+    pub fn search_messages(&self, user_id: &str, query: &str, group_filter: Option<&str>, limit: u32) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+        // Ogni termine viene quotato, così la sintassi FTS5 nell'input utente non genera errori
+        let fts_query = query
+            .split_whitespace()
+            .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if fts_query.is_empty() {
+            return Err("Search query is empty".into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {},
+                    CASE WHEN dc.group_id IS NULL THEN g.name
+                         ELSE '@' || (SELECT username FROM users
+                                      WHERE id = CASE WHEN dc.user_a = ?2 THEN dc.user_b ELSE dc.user_a END)
+                    END AS group_name,
+                    snippet(messages_fts, 1, ?4, ?5, '…', 12) AS snippet
+             FROM messages_fts f
+             JOIN messages m ON m.id = f.message_id
+             JOIN users u ON m.user_id = u.id
+             JOIN groups g ON m.group_id = g.id
+             JOIN group_memberships gm ON gm.group_id = m.group_id AND gm.user_id = ?2
+             LEFT JOIN direct_conversations dc ON dc.group_id = g.id
+             WHERE messages_fts MATCH ?1
+               AND (?3 IS NULL OR g.name = ?3)
+             ORDER BY f.rank, m.sent_at DESC
+             LIMIT ?6",
+            MESSAGE_COLUMNS
+        ))?;
+
+        let result_iter = stmt.query_map(
+            params![fts_query, user_id, group_filter, SNIPPET_MATCH_START, SNIPPET_MATCH_END, limit],
+            |row| {
+                Ok(SearchResult {
+                    message: message_from_row(row)?,
+                    group_name: row.get::<_, String>("group_name")?,
+                    snippet: row.get::<_, String>("snippet")?,
+                })
+            },
+        )?;
+
+        let mut results = Vec::new();
+        for result in result_iter {
+            results.push(result?);
+        }
+
+        Ok(results)
+    }
+
+    pub fn get_message(&self, message_id: &str) -> Result<ChatMessage, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let message = load_message(&conn, message_id).map_err(|_| "Message not found")?;
+        Ok(message)
+    }
+
+    /// Un messaggio seguito da tutte le risposte, anche indirette, in ordine cronologico.
+    /// Restituisce anche il gruppo che le contiene.
+    pub fn get_thread(&self, message_id: &str, user_id: &str) -> Result<(Group, Vec<ChatMessage>), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let target = message_target(&conn, message_id, user_id)?;
+
+        let mut stmt = conn.prepare(&format!(
+            "WITH RECURSIVE thread(id) AS (
+                 SELECT ?1
+                 UNION
+                 SELECT r.id FROM messages r JOIN thread t ON r.reply_to = t.id
+             )
+             SELECT {}
+             FROM thread t
+             JOIN messages m ON m.id = t.id
+             JOIN users u ON m.user_id = u.id
+             ORDER BY m.sent_at, m.id",
+            MESSAGE_COLUMNS
+        ))?;
+        let mut messages = stmt.query_map(params![target.id], message_from_row)?.collect::<SqlResult<Vec<_>>>()?;
+        attach_reactions(&conn, &mut messages)?;
+
+        Ok((target.group, messages))
+    }
+
+    /// Modifica il testo di un proprio messaggio (message_id può essere anche l'ID breve);
+    /// restituisce il gruppo e il messaggio aggiornato
+    pub fn edit_message(&self, message_id: &str, user_id: &str, content: &str) -> Result<(Group, ChatMessage), Box<dyn std::error::Error>> {
+        if content.trim().is_empty() {
+            return Err("The new text is empty: use /delete to remove a message".into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let target = message_target(&conn, message_id, user_id)?;
+        if target.group.archived {
+            return Err("This group is archived and read-only".into());
+        }
+        if target.author_id != user_id {
+            return Err("You can only edit your own messages".into());
+        }
+        if target.deleted {
+            return Err("This message was deleted".into());
+        }
+
+        conn.execute(
+            "UPDATE messages SET content = ?1, edited_at = ?2 WHERE id = ?3",
+            params![content, Utc::now().to_rfc3339(), target.id],
+        )?;
+
+        Ok((target.group, load_message(&conn, &target.id)?))
+    }
+
+    /// Elimina un messaggio (l'autore o un admin del gruppo). Il contenuto viene cancellato
+    /// ma la riga resta, così la cronologia mostra dove si trovava il messaggio.
+    pub fn delete_message(&self, message_id: &str, user_id: &str) -> Result<(Group, ChatMessage), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let target = message_target(&conn, message_id, user_id)?;
+        if target.group.archived {
+            return Err("This group is archived and read-only".into());
+        }
+        if target.author_id != user_id {
+            require_permission(&conn, &target.group.id, user_id, GroupAction::DeleteMessages)?;
+        }
+        if target.deleted {
+            return Err("This message was already deleted".into());
+        }
+
+        conn.execute(
+            "UPDATE messages SET content = '', deleted_at = ?1, deleted_by = ?2 WHERE id = ?3",
+            params![Utc::now().to_rfc3339(), user_id, target.id],
+        )?;
+        conn.execute("DELETE FROM message_reactions WHERE message_id = ?1", params![target.id])?;
+        conn.execute("DELETE FROM message_mentions WHERE message_id = ?1", params![target.id])?;
+
+        Ok((target.group, load_message(&conn, &target.id)?))
+    }
+
+    /// Aggiunge (o toglie, con add = false) la reazione dell'utente a un messaggio;
+    /// restituisce il gruppo e il messaggio con i conteggi aggiornati
+    pub fn set_reaction(&self, message_id: &str, user_id: &str, emoji: &str, add: bool) -> Result<(Group, ChatMessage), Box<dyn std::error::Error>> {
+        let emoji = emoji.trim();
+        if emoji.chars().count() > MAX_REACTION_LENGTH || !is_emoji(emoji) {
+            return Err(format!("'{}' is not a valid reaction: use a single emoji", emoji).into());
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let target = message_target(&conn, message_id, user_id)?;
+        if target.group.archived {
+            return Err("This group is archived and read-only".into());
+        }
+        if target.deleted {
+            return Err("This message was deleted".into());
+        }
+
+        if add {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![target.id, user_id, emoji, Utc::now().to_rfc3339()],
+            )?;
+            if inserted == 0 {
+                return Err(format!("You already reacted with {}", emoji).into());
+            }
+        } else {
+            let removed = conn.execute(
+                "DELETE FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+                params![target.id, user_id, emoji],
+            )?;
+            if removed == 0 {
+                return Err(format!("You did not react with {}", emoji).into());
+            }
+        }
+
+        Ok((target.group, load_message(&conn, &target.id)?))
+    }
+
+    /// Segna come letti tutti i messaggi del gruppo fino all'ultimo inviato
+    pub fn mark_group_read(&self, user_id: &str, group_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO group_reads (user_id, group_id, last_read_message_id, last_read_at)
+             SELECT ?1, ?2, id, sent_at FROM messages WHERE group_id = ?2
+             ORDER BY sent_at DESC, id DESC LIMIT 1
+             ON CONFLICT(user_id, group_id) DO UPDATE SET
+                 last_read_message_id = excluded.last_read_message_id,
+                 last_read_at = excluded.last_read_at",
+            params![user_id, group_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Segna come letto un messaggio appena inviato per tutti gli utenti indicati (chi si trova nel gruppo)
+    pub fn mark_message_read(&self, user_ids: &[String], group_id: &str, message_id: &str, sent_at: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO group_reads (user_id, group_id, last_read_message_id, last_read_at)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(user_id, group_id) DO UPDATE SET
+                     last_read_message_id = excluded.last_read_message_id,
+                     last_read_at = excluded.last_read_at",
+            )?;
+            for user_id in user_ids {
+                stmt.execute(params![user_id, group_id, message_id, sent_at])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Registra le menzioni di un messaggio; restituisce gli ID degli utenti menzionati
+    pub fn save_mentions(&self, message_id: &str, usernames: &[String]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let created_at = Utc::now().to_rfc3339();
+
+        let mut user_ids = Vec::new();
+        for username in usernames {
+            let user_id: String = conn.query_row(
+                "SELECT id FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get(0),
+            ).map_err(|_| format!("User '{}' not found", username))?;
+            conn.execute(
+                "INSERT OR IGNORE INTO message_mentions (message_id, user_id, created_at) VALUES (?1, ?2, ?3)",
+                params![message_id, user_id, created_at],
+            )?;
+            user_ids.push(user_id);
+        }
+
+        Ok(user_ids)
+    }
+
+    /// Menzioni non ancora lette dall'utente, dalla più vecchia, nei gruppi di cui è ancora membro
+    pub fn get_unread_mentions(&self, user_id: &str) -> Result<Vec<Mention>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, g.name AS group_name
+             FROM message_mentions mm
+             JOIN messages m ON m.id = mm.message_id
+             JOIN users u ON m.user_id = u.id
+             JOIN groups g ON m.group_id = g.id
+             JOIN group_memberships gm ON gm.group_id = m.group_id AND gm.user_id = mm.user_id
+             WHERE mm.user_id = ?1 AND mm.read_at IS NULL AND m.deleted_at IS NULL
+             ORDER BY m.sent_at",
+            MESSAGE_COLUMNS
+        ))?;
+
+        let mention_iter = stmt.query_map(params![user_id], |row| {
+            Ok(Mention {
+                message: message_from_row(row)?,
+                group_name: row.get::<_, String>("group_name")?,
+            })
+        })?;
+
+        let mut mentions = Vec::new();
+        for mention in mention_iter {
+            mentions.push(mention?);
+        }
+
+        Ok(mentions)
+    }
+
+    /// Segna come lette le menzioni dell'utente in un gruppo (None = in tutti i gruppi)
+    pub fn mark_mentions_read(&self, user_id: &str, group_id: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE message_mentions SET read_at = ?1
+             WHERE user_id = ?2 AND read_at IS NULL
+               AND (?3 IS NULL OR message_id IN (SELECT id FROM messages WHERE group_id = ?3))",
+            params![Utc::now().to_rfc3339(), user_id, group_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Apre la conversazione diretta tra l'utente e `username`, creandola al primo utilizzo
+    pub fn open_direct_conversation(&self, user_id: &str, username: &str) -> Result<DirectConversation, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut user_stmt = conn.prepare("SELECT id FROM users WHERE username = ?1")?;
+        let partner_id: String = user_stmt.query_row(params![username], |row| row.get(0))
+            .map_err(|_| "User not found")?;
+        if partner_id == user_id {
+            return Err("You cannot open a direct conversation with yourself".into());
+        }
+
+        if let Some(conversation) = direct_conversation(&conn, user_id, &partner_id)? {
+            return Ok(conversation);
+        }
+
+        // Il gruppo nascosto ha un nome interno derivato dalla coppia, così resta unico
+        let (user_a, user_b) = if user_id < partner_id.as_str() { (user_id, partner_id.as_str()) } else { (partner_id.as_str(), user_id) };
+        let group_id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT INTO groups (id, name, creator_id, created_at, visibility) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![group_id, format!("{}{}:{}", DIRECT_GROUP_PREFIX, user_a, user_b), user_id, now, GroupVisibility::Private.as_str()],
+        )?;
+        for member_id in [user_a, user_b] {
+            tx.execute(
+                "INSERT INTO group_memberships (id, group_id, user_id, joined_at) VALUES (?1, ?2, ?3, ?4)",
+                params![Uuid::new_v4().to_string(), group_id, member_id, now],
+            )?;
+        }
+        tx.execute(
+            "INSERT INTO direct_conversations (group_id, user_a, user_b, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![group_id, user_a, user_b, now],
+        )?;
+        tx.commit()?;
+
+        direct_conversation(&conn, user_id, &partner_id)?
+            .ok_or_else(|| "Failed to create the direct conversation".into())
+    }
+
+    /// Conversazione diretta già esistente tra l'utente e `username`
+    pub fn get_direct_conversation(&self, user_id: &str, username: &str) -> Result<DirectConversation, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut user_stmt = conn.prepare("SELECT id FROM users WHERE username = ?1")?;
+        let partner_id: String = user_stmt.query_row(params![username], |row| row.get(0))
+            .map_err(|_| "User not found")?;
+
+        direct_conversation(&conn, user_id, &partner_id)?
+            .ok_or_else(|| format!("No direct conversation with '{}': use /dm {} to start one", username, username).into())
+    }
+
+    /// Conversazioni dirette dell'utente, dalla più recente
+    pub fn get_direct_conversations(&self, user_id: &str) -> Result<Vec<DirectConversation>, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT CASE WHEN dc.user_a = ?1 THEN dc.user_b ELSE dc.user_a END
+             FROM direct_conversations dc
+             WHERE dc.user_a = ?1 OR dc.user_b = ?1
+             ORDER BY COALESCE((SELECT MAX(sent_at) FROM messages WHERE group_id = dc.group_id), dc.created_at) DESC"
+        )?;
+        let partner_iter = stmt.query_map(params![user_id], |row| row.get::<_, String>(0))?;
+
+        let mut conversations = Vec::new();
+        for partner_id in partner_iter {
+            if let Some(conversation) = direct_conversation(&conn, user_id, &partner_id?)? {
+                conversations.push(conversation);
+            }
+        }
+
+        Ok(conversations)
+    }
+
+    /// Nome con cui l'utente vede il gruppo: "@altro_utente" per le conversazioni dirette
+    pub fn conversation_name(&self, group_name: &str, user_id: &str) -> String {
+        if !group_name.starts_with(DIRECT_GROUP_PREFIX) {
+            return group_name.to_string();
+        }
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT '@' || u.username
+             FROM groups g
+             JOIN direct_conversations dc ON dc.group_id = g.id
+             JOIN users u ON u.id = CASE WHEN dc.user_a = ?2 THEN dc.user_b ELSE dc.user_a END
+             WHERE g.name = ?1",
+            params![group_name, user_id],
+            |row| row.get(0),
+        ).unwrap_or_else(|_| group_name.to_string())
+    }
+
+    pub fn get_username(&self, user_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT username FROM users WHERE id = ?1")?;
+        let username: String = stmt.query_row(params![user_id], |row| row.get(0))
+            .map_err(|_| "User not found")?;
+        Ok(username)
+    }
+
     pub fn get_group_id(&self, group_name: &str) -> Result<String, Box<dyn std::error::Error>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare("SELECT id FROM groups WHERE name = ?1")?;
@@ -443,3 +1737,682 @@ impl Database {
         Ok(group_id)
     }
 }
+
+/// Ruolo dell'utente nel gruppo (None se non ne è membro)
+fn member_role(conn: &Connection, group_id: &str, user_id: &str) -> SqlResult<Option<GroupRole>> {
+    let mut stmt = conn.prepare("SELECT role FROM group_memberships WHERE group_id = ?1 AND user_id = ?2")?;
+    let mut rows = stmt.query(params![group_id, user_id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(GroupRole::from_db(&row.get::<_, String>(0)?))),
+        None => Ok(None),
+    }
+}
+
+/// Verifica che l'utente sia membro del gruppo e abbia un ruolo sufficiente per l'azione
+/// (le conversazioni dirette non si possono gestire come gruppi)
+fn require_permission(conn: &Connection, group_id: &str, user_id: &str, action: GroupAction) -> Result<GroupRole, Box<dyn std::error::Error>> {
+    if is_direct(conn, group_id)? {
+        return Err("This is not possible in a direct conversation".into());
+    }
+    let role = member_role(conn, group_id, user_id)?
+        .ok_or("You are not a member of this group")?;
+    if !role.allows(action) {
+        return Err(action.denied_message().into());
+    }
+    Ok(role)
+}
+
+/// Controlla che chi modera possa agire sull'utente indicato: serve il permesso di espellere
+/// e un ruolo superiore a quello del bersaglio. Restituisce l'ID del bersaglio e il suo ruolo (None se non è membro).
+fn moderation_target(conn: &Connection, group_id: &str, username: &str, moderator_id: &str) -> Result<(String, Option<GroupRole>), Box<dyn std::error::Error>> {
+    let moderator_role = require_permission(conn, group_id, moderator_id, GroupAction::Kick)?;
+
+    let mut user_stmt = conn.prepare("SELECT id FROM users WHERE username = ?1")?;
+    let user_id: String = user_stmt.query_row(params![username], |row| row.get(0))
+        .map_err(|_| "User not found")?;
+
+    if user_id == moderator_id {
+        return Err("You cannot remove yourself: use /quit-group instead".into());
+    }
+    let target_role = member_role(conn, group_id, &user_id)?;
+    if target_role.is_some_and(|role| role >= moderator_role) {
+        return Err(format!("You cannot remove '{}': their role is not lower than yours", username).into());
+    }
+    Ok((user_id, target_role))
+}
+
+fn find_group(conn: &Connection, group_name: &str) -> Result<Group, Box<dyn std::error::Error>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, creator_id, created_at, visibility, description, topic, archived_at IS NOT NULL
+         FROM groups WHERE name = ?1"
+    )?;
+    let group = stmt.query_row(params![group_name], |row| {
+        Ok(Group {
+            id: row.get::<_, String>(0)?,
+            name: row.get::<_, String>(1)?,
+            creator_id: row.get::<_, String>(2)?,
+            created_at: row.get::<_, String>(3)?,
+            members: Vec::new(),
+            visibility: GroupVisibility::from_db(&row.get::<_, String>(4)?),
+            description: row.get::<_, Option<String>>(5)?,
+            topic: row.get::<_, Option<String>>(6)?,
+            archived: row.get::<_, bool>(7)?,
+            unread_count: 0,
+        })
+    }).map_err(|_| format!("Group '{}' not found", group_name))?;
+    Ok(group)
+}
+
+/// Fine di un ban di ttl_secs secondi a partire da now
+fn ban_expiry(now: DateTime<Utc>, ttl_secs: i64) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+    expiry_after(now, ttl_secs, MAX_BAN_DURATION_SECS, "Ban duration")
+}
+
+/// Scadenza a ttl_secs secondi da now; what descrive la durata nei messaggi d'errore
+fn expiry_after(now: DateTime<Utc>, ttl_secs: i64, max_secs: i64, what: &str) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+    if ttl_secs <= 0 || ttl_secs > max_secs {
+        return Err(format!("{} must be between 1 second and {} days", what, max_secs / 86_400).into());
+    }
+    now.timestamp()
+        .checked_add(ttl_secs)
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .ok_or_else(|| format!("{} is too long", what).into())
+}
+
+/// Costruisce un ChatMessage da una riga che contiene le colonne di MESSAGE_COLUMNS
+fn message_from_row(row: &Row) -> SqlResult<ChatMessage> {
+    Ok(ChatMessage {
+        id: row.get("id")?,
+        content: row.get("content")?,
+        username: row.get("username")?,
+        timestamp: row.get("sent_at")?,
+        edited_at: row.get("edited_at")?,
+        deleted: row.get("deleted")?,
+        reply_to: match row.get::<_, Option<String>>("reply_to")? {
+            Some(id) => Some(QuotedMessage {
+                id,
+                username: row.get::<_, Option<String>>("reply_username")?.unwrap_or_default(),
+                content: row.get("reply_content")?,
+            }),
+            None => None,
+        },
+        // Caricate a parte da attach_reactions
+        reactions: Vec::new(),
+    })
+}
+
+fn load_message(conn: &Connection, message_id: &str) -> SqlResult<ChatMessage> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM messages m JOIN users u ON m.user_id = u.id WHERE m.id = ?1",
+        MESSAGE_COLUMNS
+    ))?;
+    let mut message = stmt.query_row(params![message_id], message_from_row)?;
+    attach_reactions(conn, std::slice::from_mut(&mut message))?;
+    Ok(message)
+}
+
+/// Aggiunge a ogni messaggio il conteggio delle sue reazioni, con una query per gruppo di messaggi
+fn attach_reactions(conn: &Connection, messages: &mut [ChatMessage]) -> SqlResult<()> {
+    for batch in messages.chunks_mut(REACTIONS_BATCH_SIZE) {
+        let placeholders = vec!["?"; batch.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT message_id, emoji, COUNT(*) FROM message_reactions
+             WHERE message_id IN ({})
+             GROUP BY message_id, emoji
+             ORDER BY MIN(created_at), emoji",
+            placeholders
+        ))?;
+        let rows = stmt.query_map(
+            rusqlite::params_from_iter(batch.iter().map(|message| message.id.as_str())),
+            |row| Ok((row.get::<_, String>(0)?, ReactionCount { emoji: row.get(1)?, count: row.get(2)? })),
+        )?;
+
+        let mut counts: HashMap<String, Vec<ReactionCount>> = HashMap::new();
+        for row in rows {
+            let (message_id, count) = row?;
+            counts.entry(message_id).or_default().push(count);
+        }
+        for message in batch.iter_mut() {
+            message.reactions = counts.remove(&message.id).unwrap_or_default();
+        }
+    }
+    Ok(())
+}
+
+/// Una singola emoji, anche composta: caratteri pittografici uniti da ZWJ, selettori di variante,
+/// tonalità della pelle, indicatori regionali (bandiere), tag e keycap (es. 1️⃣)
+fn is_emoji(value: &str) -> bool {
+    let is_pictographic = |c: char| matches!(c as u32,
+        0x1F000..=0x1FAFF   // simboli, pittogrammi, emoticon, trasporti, bandiere, tonalità della pelle
+        | 0x2600..=0x27BF   // simboli vari e dingbat (☀ ✅ ❤)
+        | 0x2300..=0x23FF   // simboli tecnici (⌚ ⏰)
+        | 0x2B00..=0x2BFF   // frecce e forme (⬆ ⭐ ⭕)
+        | 0x2190..=0x21FF   // frecce (↔ ↩)
+        | 0x25A0..=0x25FF   // forme geometriche (▶ ◀)
+        | 0x00A9 | 0x00AE | 0x203C | 0x2049 | 0x2122 | 0x2139 | 0x24C2 | 0x3030 | 0x303D | 0x3297 | 0x3299);
+    let is_component = |c: char| matches!(c as u32,
+        0x200D              // zero width joiner
+        | 0xFE0E | 0xFE0F   // selettori di variante
+        | 0x20E3            // keycap
+        | 0xE0020..=0xE007F) // tag (bandiere delle regioni)
+        || matches!(c, '0'..='9' | '#' | '*');
+
+    value.chars().all(|c| is_pictographic(c) || is_component(c))
+        && value.chars().any(|c| is_pictographic(c) || c == '\u{20E3}')
+}
+
+/// Messaggio che un utente vuole modificare o eliminare
+struct MessageTarget {
+    id: String,
+    group: Group,
+    author_id: String,
+    deleted: bool,
+}
+
+/// Trova un messaggio dei gruppi di cui user_id è membro a partire dal suo ID o da un prefisso
+/// dell'ID (l'ID breve mostrato dal client).
+fn message_target(conn: &Connection, message_id: &str, user_id: &str) -> Result<MessageTarget, Box<dyn std::error::Error>> {
+    let message_id = message_id.trim().trim_start_matches('#');
+    if message_id.len() < MIN_MESSAGE_ID_PREFIX {
+        return Err(format!("Message id '{}' is too short: use at least {} characters", message_id, MIN_MESSAGE_ID_PREFIX).into());
+    }
+
+    // Gli ID che iniziano con il prefisso sono un intervallo della chiave primaria
+    let mut stmt = conn.prepare(
+        "SELECT m.id, g.name, m.user_id, m.deleted_at IS NOT NULL
+         FROM messages m
+         JOIN groups g ON g.id = m.group_id
+         JOIN group_memberships gm ON gm.group_id = m.group_id AND gm.user_id = ?2
+         WHERE m.id >= ?1 AND m.id < ?1 || char(1114111)
+         LIMIT 2"
+    )?;
+    let mut matches = stmt
+        .query_map(params![message_id, user_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, bool>(3)?))
+        })?
+        .collect::<SqlResult<Vec<_>>>()?;
+    if matches.len() > 1 {
+        return Err(format!("Message id '{}' is ambiguous: use more characters", message_id).into());
+    }
+    let (id, group_name, author_id, deleted) = matches.pop()
+        .ok_or_else(|| format!("Message '{}' not found", message_id))?;
+
+    let group = find_group(conn, &group_name)?;
+    Ok(MessageTarget { id, group, author_id, deleted })
+}
+
+fn is_direct(conn: &Connection, group_id: &str) -> SqlResult<bool> {
+    let mut stmt = conn.prepare("SELECT COUNT(*) FROM direct_conversations WHERE group_id = ?1")?;
+    let count: i64 = stmt.query_row(params![group_id], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+fn member_ids(conn: &Connection, group_id: &str) -> SqlResult<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT user_id FROM group_memberships WHERE group_id = ?1")?;
+    let ids = stmt.query_map(params![group_id], |row| row.get::<_, String>(0))?;
+    ids.collect()
+}
+
+fn is_archived(conn: &Connection, group_id: &str) -> SqlResult<bool> {
+    let mut stmt = conn.prepare("SELECT archived_at IS NOT NULL FROM groups WHERE id = ?1")?;
+    stmt.query_row(params![group_id], |row| row.get(0))
+}
+
+/// Conversazione diretta tra due utenti vista da user_id (None se non esiste ancora)
+fn direct_conversation(conn: &Connection, user_id: &str, partner_id: &str) -> SqlResult<Option<DirectConversation>> {
+    let mut stmt = conn.prepare(
+        "SELECT g.id, g.name, u.username
+         FROM direct_conversations dc
+         JOIN groups g ON g.id = dc.group_id
+         JOIN users u ON u.id = ?2
+         WHERE (dc.user_a = ?1 AND dc.user_b = ?2) OR (dc.user_a = ?2 AND dc.user_b = ?1)"
+    )?;
+    let mut rows = stmt.query(params![user_id, partner_id])?;
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+    let group_id: String = row.get(0)?;
+    let group_name: String = row.get(1)?;
+    let partner_username: String = row.get(2)?;
+
+    let mut last_stmt = conn.prepare(&format!(
+        "SELECT {}
+         FROM messages m
+         JOIN users u ON m.user_id = u.id
+         WHERE m.group_id = ?1
+         ORDER BY m.sent_at DESC
+         LIMIT 1",
+        MESSAGE_COLUMNS
+    ))?;
+    let mut last_rows = last_stmt.query(params![group_id])?;
+    let last_message = match last_rows.next()? {
+        Some(row) => Some(message_from_row(row)?),
+        None => None,
+    };
+
+    Ok(Some(DirectConversation {
+        group_id,
+        group_name,
+        partner_id: partner_id.to_string(),
+        partner_username,
+        last_message,
+    }))
+}
+
+/// Registra l'uscita dal gruppo: per rientrare servirà un nuovo invito
+fn record_departure(conn: &Connection, group_id: &str, user_id: &str) -> SqlResult<()> {
+    // Usa INSERT OR REPLACE per evitare errori se l'utente ha già abbandonato questo gruppo in passato
+    conn.execute(
+        "INSERT OR REPLACE INTO group_departures (id, group_id, user_id, left_at) VALUES (?1, ?2, ?3, ?4)",
+        params![Uuid::new_v4().to_string(), group_id, user_id, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Ban attivo dell'utente nel gruppo: Some(scadenza) se bandito, con scadenza None per i ban permanenti.
+/// I ban scaduti vengono eliminati.
+fn active_ban(conn: &Connection, group_id: &str, user_id: &str) -> SqlResult<Option<Option<i64>>> {
+    conn.execute(
+        "DELETE FROM group_bans WHERE group_id = ?1 AND user_id = ?2 AND expires_at IS NOT NULL AND expires_at < ?3",
+        params![group_id, user_id, Utc::now().timestamp()],
+    )?;
+    let mut stmt = conn.prepare("SELECT expires_at FROM group_bans WHERE group_id = ?1 AND user_id = ?2")?;
+    let mut rows = stmt.query(params![group_id, user_id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get::<_, Option<i64>>(0)?)),
+        None => Ok(None),
+    }
+}
+
+fn describe_ban(expires_at: Option<i64>) -> String {
+    match expires_at.and_then(|secs| DateTime::from_timestamp(secs, 0)) {
+        Some(expires_at) => format!("until {}", expires_at.format("%Y-%m-%d %H:%M UTC")),
+        None => "permanently".to_string(),
+    }
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> SqlResult<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if row.get::<_, String>(1)? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_database() -> Database {
+        Database::from_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    /// Crea un utente senza passare da bcrypt, che nei test sarebbe troppo lento
+    fn add_user(db: &Database, username: &str) -> String {
+        let user_id = format!("user-{}", username);
+        db.conn.lock().unwrap().execute(
+            "INSERT INTO users (id, username, password_hash, created_at) VALUES (?1, ?2, '', ?3)",
+            params![user_id, username, Utc::now().to_rfc3339()],
+        ).unwrap();
+        user_id
+    }
+
+    /// Inserisce un messaggio con un ID scelto dal test
+    fn add_message(db: &Database, id: &str, group_name: &str, user_id: &str) {
+        let group_id = db.get_group_id(group_name).unwrap();
+        db.conn.lock().unwrap().execute(
+            "INSERT INTO messages (id, group_id, user_id, content, sent_at) VALUES (?1, ?2, ?3, 'hello', ?4)",
+            params![id, group_id, user_id, Utc::now().to_rfc3339()],
+        ).unwrap();
+    }
+
+    fn unread_count(db: &Database, user_id: &str, group_name: &str) -> u32 {
+        db.get_user_groups(user_id).unwrap()
+            .into_iter()
+            .find(|group| group.name == group_name)
+            .map(|group| group.unread_count)
+            .unwrap()
+    }
+
+    fn message_ids(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|message| message.id.as_str()).collect()
+    }
+
+    #[test]
+    fn ban_expiry_is_bounded() {
+        let now = Utc::now();
+        assert_eq!(ban_expiry(now, 3600).unwrap().timestamp(), now.timestamp() + 3600);
+        assert!(ban_expiry(now, MAX_BAN_DURATION_SECS).is_ok());
+        assert!(ban_expiry(now, MAX_BAN_DURATION_SECS + 1).is_err());
+        assert!(ban_expiry(now, i64::MAX).is_err());
+        assert!(ban_expiry(now, 0).is_err());
+    }
+
+    #[test]
+    fn overflowing_ban_is_rejected_without_poisoning_the_database() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let member = add_user(&db, "member");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        db.join_group("ops", &member).unwrap();
+
+        assert!(db.ban_member("ops", "member", &owner, Some(i64::MAX)).is_err());
+        assert_eq!(db.get_group_members("ops").unwrap(), vec!["member", "owner"]);
+
+        let ban = db.ban_member("ops", "member", &owner, Some(60)).unwrap();
+        assert!(ban.expires_at.is_some_and(|expires_at| expires_at > Utc::now()));
+        assert_eq!(db.get_group_members("ops").unwrap(), vec!["owner"]);
+    }
+
+    #[test]
+    fn expired_ban_no_longer_blocks_invites() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let member = add_user(&db, "member");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        db.join_group("ops", &member).unwrap();
+        db.ban_member("ops", "member", &owner, Some(60)).unwrap();
+        assert!(db.invite_user_to_group("ops", "member", &owner, None).is_err());
+
+        db.conn.lock().unwrap().execute(
+            "UPDATE group_bans SET expires_at = ?1",
+            params![Utc::now().timestamp() - 1],
+        ).unwrap();
+        db.invite_user_to_group("ops", "member", &owner, None).unwrap();
+        db.accept_invite("ops", &member).unwrap();
+    }
+
+    #[test]
+    fn roles_allow_only_their_actions() {
+        assert!(!GroupRole::Member.allows(GroupAction::Kick));
+        assert!(!GroupRole::Member.allows(GroupAction::EditInfo));
+        assert!(GroupRole::Admin.allows(GroupAction::Kick));
+        assert!(GroupRole::Admin.allows(GroupAction::DeleteMessages));
+        assert!(!GroupRole::Admin.allows(GroupAction::Archive));
+        assert!(!GroupRole::Admin.allows(GroupAction::Delete));
+        assert!(!GroupRole::Admin.allows(GroupAction::ChangeRoles));
+        assert!(GroupRole::Owner.allows(GroupAction::Delete));
+    }
+
+    #[test]
+    fn role_checks_are_enforced() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let admin = add_user(&db, "admin");
+        let member = add_user(&db, "member");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        db.join_group("ops", &admin).unwrap();
+        db.join_group("ops", &member).unwrap();
+        db.set_member_role("ops", "admin", GroupRole::Admin, &owner).unwrap();
+
+        assert!(db.rename_group("ops", "dev", &member).is_err());
+        assert!(db.kick_member("ops", "admin", &member).is_err());
+        assert!(db.set_member_role("ops", "member", GroupRole::Admin, &admin).is_err());
+        assert!(db.set_group_archived("ops", true, &admin).is_err());
+        assert!(db.kick_member("ops", "owner", &admin).is_err());
+
+        db.set_group_topic("ops", Some("release"), &admin).unwrap();
+        assert_eq!(db.kick_member("ops", "member", &admin).unwrap(), member);
+    }
+
+    #[test]
+    fn archived_group_is_read_only_and_deletion_is_owner_only() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let member = add_user(&db, "member");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        db.join_group("ops", &member).unwrap();
+
+        let (group, members) = db.set_group_archived("ops", true, &owner).unwrap();
+        assert!(group.archived);
+        assert_eq!(members.len(), 2);
+        assert!(db.send_message("ops", &member, "hi", None).is_err());
+        assert!(db.get_user_groups(&member).unwrap().is_empty());
+
+        assert!(db.delete_group("ops", &member).is_err());
+        db.delete_group("ops", &owner).unwrap();
+        assert!(db.get_group("ops").is_err());
+    }
+
+    #[test]
+    fn short_message_ids_must_be_unambiguous() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let outsider = add_user(&db, "outsider");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        add_message(&db, "abcd1111", "ops", &owner);
+        add_message(&db, "abcd2222", "ops", &owner);
+
+        let conn = db.conn.lock().unwrap();
+        let ambiguous = message_target(&conn, "abcd", &owner).err().unwrap().to_string();
+        assert!(ambiguous.contains("ambiguous"), "{}", ambiguous);
+        assert_eq!(message_target(&conn, "#abcd1", &owner).unwrap().id, "abcd1111");
+        assert_eq!(message_target(&conn, "abcd2222", &owner).unwrap().id, "abcd2222");
+        assert!(message_target(&conn, "abc", &owner).is_err());
+        assert!(message_target(&conn, "abcd3", &owner).is_err());
+        assert!(message_target(&conn, "abcd1", &outsider).is_err());
+    }
+
+    #[test]
+    fn deleted_messages_become_tombstones() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let author = add_user(&db, "author");
+        let member = add_user(&db, "member");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        db.join_group("ops", &author).unwrap();
+        db.join_group("ops", &member).unwrap();
+        add_message(&db, "msg-0001", "ops", &author);
+
+        assert!(db.edit_message("msg-0001", &member, "changed").is_err());
+        assert!(db.delete_message("msg-0001", &member).is_err());
+        let (_, edited) = db.edit_message("msg-0001", &author, "changed").unwrap();
+        assert!(edited.edited_at.is_some());
+
+        let (_, deleted) = db.delete_message("msg-0001", &owner).unwrap();
+        assert!(deleted.deleted);
+        assert!(deleted.content.is_empty());
+        assert!(db.edit_message("msg-0001", &author, "again").is_err());
+    }
+
+    #[test]
+    fn unread_counts_follow_the_last_read_message() {
+        let db = test_database();
+        let alice = add_user(&db, "alice");
+        let bob = add_user(&db, "bob");
+        db.create_group("ops", &alice, GroupVisibility::Public).unwrap();
+        db.join_group("ops", &bob).unwrap();
+        let group_id = db.get_group_id("ops").unwrap();
+
+        for content in ["one", "two", "three"] {
+            db.send_message("ops", &alice, content, None).unwrap();
+        }
+        assert_eq!(unread_count(&db, &bob, "ops"), 3);
+        // I propri messaggi non contano come non letti
+        assert_eq!(unread_count(&db, &alice, "ops"), 0);
+
+        db.mark_group_read(&bob, &group_id).unwrap();
+        assert_eq!(unread_count(&db, &bob, "ops"), 0);
+
+        let sent = db.send_message("ops", &alice, "four", None).unwrap();
+        db.send_message("ops", &alice, "five", None).unwrap();
+        assert_eq!(unread_count(&db, &bob, "ops"), 2);
+
+        db.mark_message_read(std::slice::from_ref(&bob), &group_id, &sent[0], &sent[4]).unwrap();
+        assert_eq!(unread_count(&db, &bob, "ops"), 1);
+    }
+
+    #[test]
+    fn invitations_expire() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let guest = add_user(&db, "guest");
+        db.create_group("ops", &owner, GroupVisibility::Private).unwrap();
+
+        assert!(db.invite_user_to_group("ops", "guest", &owner, Some(i64::MAX)).is_err());
+        assert!(db.invite_user_to_group("ops", "guest", &owner, Some(MAX_INVITE_TTL_SECS + 1)).is_err());
+
+        let invite = db.invite_user_to_group("ops", "guest", &owner, Some(60)).unwrap();
+        assert!(invite.expires_at.is_some_and(|expires_at| expires_at > Utc::now()));
+        assert_eq!(db.get_pending_invites(&guest).unwrap().len(), 1);
+
+        db.conn.lock().unwrap().execute(
+            "UPDATE group_invites SET expires_at = ?1",
+            params![Utc::now().timestamp() - 1],
+        ).unwrap();
+        assert!(db.get_pending_invites(&guest).unwrap().is_empty());
+        assert!(db.accept_invite("ops", &guest).is_err());
+    }
+
+    #[test]
+    fn invitation_without_expiry_can_be_accepted_once() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let guest = add_user(&db, "guest");
+        db.create_group("ops", &owner, GroupVisibility::Private).unwrap();
+
+        let invite = db.invite_user_to_group("ops", "guest", &owner, None).unwrap();
+        assert!(invite.expires_at.is_none());
+        db.accept_invite("ops", &guest).unwrap();
+        assert!(db.accept_invite("ops", &guest).is_err());
+        assert_eq!(db.get_group_members("ops").unwrap(), vec!["guest", "owner"]);
+    }
+
+    #[test]
+    fn session_duration_is_bounded() {
+        let db = test_database();
+        let user = add_user(&db, "user");
+
+        assert!(db.create_session(&user, i64::MAX).is_err());
+        assert!(db.create_session(&user, MAX_SESSION_TTL_SECS + 1).is_err());
+
+        let token = db.create_session(&user, 60).unwrap();
+        assert!(db.resume_session(&token, i64::MAX).is_err());
+        let (resumed_user, group) = db.resume_session(&token, 60).unwrap();
+        assert_eq!(resumed_user, user);
+        assert!(group.is_none());
+    }
+
+    #[test]
+    fn history_pages_follow_the_cursor() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let outsider = add_user(&db, "outsider");
+        db.create_group("ops", &owner, GroupVisibility::Private).unwrap();
+        db.create_group("other", &owner, GroupVisibility::Private).unwrap();
+        for id in ["m1", "m2", "m3", "m4", "m5"] {
+            add_message(&db, id, "ops", &owner);
+        }
+        add_message(&db, "x1", "other", &owner);
+        // Stesso sent_at per tutti: l'ordine dipende solo dall'ID usato come spareggio del cursore
+        db.conn.lock().unwrap().execute("UPDATE messages SET sent_at = ?1", params![Utc::now().to_rfc3339()]).unwrap();
+
+        let (page, has_more) = db.get_message_history("ops", &owner, None, None, 2).unwrap();
+        assert_eq!((message_ids(&page), has_more), (vec!["m4", "m5"], true));
+        let (page, has_more) = db.get_message_history("ops", &owner, Some("m4"), None, 2).unwrap();
+        assert_eq!((message_ids(&page), has_more), (vec!["m2", "m3"], true));
+        let (page, has_more) = db.get_message_history("ops", &owner, Some("m2"), None, 2).unwrap();
+        assert_eq!((message_ids(&page), has_more), (vec!["m1"], false));
+
+        let (page, has_more) = db.get_message_history("ops", &owner, None, Some("m1"), 2).unwrap();
+        assert_eq!((message_ids(&page), has_more), (vec!["m2", "m3"], true));
+        let (page, has_more) = db.get_message_history("ops", &owner, None, Some("m4"), 2).unwrap();
+        assert_eq!((message_ids(&page), has_more), (vec!["m5"], false));
+
+        assert!(db.get_message_history("ops", &owner, Some("m4"), Some("m1"), 2).is_err());
+        assert!(db.get_message_history("ops", &owner, Some("x1"), None, 2).is_err());
+        assert!(db.get_message_history("ops", &outsider, None, None, 2).is_err());
+    }
+
+    #[test]
+    fn search_only_covers_groups_of_the_user() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let member = add_user(&db, "member");
+        db.create_group("ops", &owner, GroupVisibility::Public).unwrap();
+        db.create_group("secret", &owner, GroupVisibility::Private).unwrap();
+        db.join_group("ops", &member).unwrap();
+        db.send_message("ops", &owner, "deploy at noon", None).unwrap();
+        db.send_message("secret", &owner, "deploy the secret plan", None).unwrap();
+
+        let groups = |user_id: &str, filter: Option<&str>| -> Vec<String> {
+            db.search_messages(user_id, "deploy", filter, 10).unwrap()
+                .into_iter()
+                .map(|result| result.group_name)
+                .collect()
+        };
+        assert_eq!(groups(&member, None), vec!["ops"]);
+        assert!(groups(&member, Some("secret")).is_empty());
+        assert_eq!(groups(&owner, Some("secret")), vec!["secret"]);
+        assert_eq!(groups(&owner, None).len(), 2);
+
+        // La sintassi FTS5 nell'input viene trattata come testo
+        assert!(db.search_messages(&owner, "deploy\" OR", None, 10).unwrap().is_empty());
+        assert!(db.search_messages(&owner, "   ", None, 10).is_err());
+
+        db.leave_group("ops", &member).unwrap();
+        assert!(groups(&member, None).is_empty());
+    }
+
+    #[test]
+    fn direct_conversations_are_shared_and_hidden_from_groups() {
+        let db = test_database();
+        let alice = add_user(&db, "alice");
+        let bob = add_user(&db, "bob");
+        add_user(&db, "carol");
+
+        let conversation = db.open_direct_conversation(&alice, "bob").unwrap();
+        assert_eq!(conversation.partner_username, "bob");
+        assert_eq!(db.open_direct_conversation(&bob, "alice").unwrap().group_id, conversation.group_id);
+        assert!(db.open_direct_conversation(&alice, "alice").is_err());
+        assert!(db.open_direct_conversation(&alice, "nobody").is_err());
+        assert!(db.get_direct_conversation(&alice, "carol").is_err());
+
+        assert_eq!(db.get_group_count().unwrap(), 0);
+        assert_eq!(db.conversation_name(&conversation.group_name, &alice), "@bob");
+        assert_eq!(db.conversation_name(&conversation.group_name, &bob), "@alice");
+
+        db.send_message(&conversation.group_name, &bob, "hi alice", None).unwrap();
+        let results = db.search_messages(&alice, "alice", None, 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].group_name, "@bob");
+        assert!(db.get_direct_conversations(&alice).unwrap()[0].last_message.is_some());
+
+        // Una conversazione diretta resta fra i due utenti
+        assert!(db.leave_group(&conversation.group_name, &alice).is_err());
+        assert!(db.invite_user_to_group(&conversation.group_name, "carol", &alice, None).is_err());
+        assert!(db.join_group(&conversation.group_name, &add_user(&db, "dave")).is_err());
+    }
+
+    #[test]
+    fn private_groups_need_an_invitation() {
+        let db = test_database();
+        let owner = add_user(&db, "owner");
+        let guest = add_user(&db, "guest");
+        db.create_group("lobby", &owner, GroupVisibility::Public).unwrap();
+        db.create_group("secret", &owner, GroupVisibility::Private).unwrap();
+
+        let listed: Vec<String> = db.get_public_groups(&guest).unwrap().into_iter().map(|group| group.name).collect();
+        assert_eq!(listed, vec!["lobby"]);
+        assert!(db.get_group_members_with_roles("lobby", &guest).is_ok());
+        assert!(db.get_group_members_with_roles("secret", &guest).is_err());
+        assert!(db.join_group("secret", &guest).is_err());
+
+        // Entrare con un invito valido lo consuma
+        db.invite_user_to_group("secret", "guest", &owner, None).unwrap();
+        db.join_group("secret", &guest).unwrap();
+        assert!(db.get_pending_invites(&guest).unwrap().is_empty());
+        assert_eq!(db.get_group_members_with_roles("secret", &guest).unwrap().len(), 2);
+
+        // Chi lascia un gruppo privato ha bisogno di un nuovo invito per rientrare
+        db.leave_group("secret", &guest).unwrap();
+        assert!(db.join_group("secret", &guest).is_err());
+
+        db.set_group_visibility("secret", GroupVisibility::Public, &owner).unwrap();
+        assert_eq!(db.get_public_groups(&guest).unwrap().len(), 2);
+        assert!(db.set_group_visibility("secret", GroupVisibility::Private, &guest).is_err());
+    }
+}
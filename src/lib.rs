@@ -1,5 +1,7 @@
 pub mod common;
+pub mod config;
 pub mod protocol;
 pub mod database;
+pub mod tls;
 
 pub use database::Database;
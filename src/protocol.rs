@@ -1,23 +1,134 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use crate::common::*;
 
+/// Versione corrente del protocollo.
+/// La versione 2 introduce l'envelope con request_id; i frame della versione 1 restano validi.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Versione minima del protocollo accettata dal server
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Funzionalità opzionali negoziate durante l'handshake Hello/Welcome
+pub mod capabilities {
+    /// Cronologia paginata (FetchHistory / HistoryPage)
+    pub const HISTORY: &str = "history";
+    /// Ricerca full-text (SearchMessages / SearchResults)
+    pub const SEARCH: &str = "search";
+    /// Token di sessione (Resume / Logout)
+    pub const SESSIONS: &str = "sessions";
+    /// Il client risponde ai Ping del server con Pong
+    pub const HEARTBEAT: &str = "heartbeat";
+    /// Ruoli e moderazione nei gruppi (PromoteMember / DemoteMember / KickMember / BanMember / GroupMemberList)
+    pub const ROLES: &str = "roles";
+    /// Conversazioni dirette (OpenDirect / ListDirects / DirectList)
+    pub const DIRECT: &str = "direct";
+    /// Gruppi pubblici e privati (ListPublicGroups / SetGroupVisibility / CreateGroup con visibility)
+    pub const BROWSE: &str = "browse";
+    /// Nome, argomento e descrizione modificabili (RenameGroup / SetGroupTopic / SetGroupDescription / GroupUpdated)
+    pub const METADATA: &str = "metadata";
+    /// Archiviazione ed eliminazione dei gruppi (ArchiveGroup / UnarchiveGroup / DeleteGroup / ListArchivedGroups)
+    pub const ARCHIVE: &str = "archive";
+    /// Modifica ed eliminazione dei messaggi (EditMessage / DeleteMessage / MessageUpdated)
+    pub const EDIT: &str = "edit";
+    /// Risposte e thread (SendMessage con reply_to / FetchThread / Thread)
+    pub const THREADS: &str = "threads";
+    /// Reazioni ai messaggi (React / Unreact / ReactionsUpdated)
+    pub const REACTIONS: &str = "reactions";
+    /// Menzioni @utente nei messaggi (Mentioned / ListMentions / MentionList)
+    pub const MENTIONS: &str = "mentions";
+    /// Notifica immediata degli inviti ricevuti (InviteReceived); senza, gli inviti si vedono con ListInvites
+    pub const INVITES: &str = "invites";
+
+    /// Tutte le funzionalità supportate da questa versione
+    pub const ALL: &[&str] = &[HISTORY, SEARCH, SESSIONS, HEARTBEAT, ROLES, DIRECT, BROWSE, METADATA, ARCHIVE, EDIT, THREADS, REACTIONS, MENTIONS, INVITES];
+}
+
+/// Restituisce le funzionalità offerte dal peer che sono supportate anche localmente
+pub fn negotiate_capabilities(offered: &[String]) -> Vec<String> {
+    capabilities::ALL
+        .iter()
+        .filter(|capability| offered.iter().any(|o| o == *capability))
+        .map(|capability| capability.to_string())
+        .collect()
+}
+
 /// Messaggi di protocollo per la comunicazione client-server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProtocolMessage {
+    // Handshake
+    Hello { protocol_version: u32, client_name: String, capabilities: Vec<String> },
+    Welcome { server_version: String, capabilities: Vec<String> },
+
     // Autenticazione
     Register { username: String, password: String },
     Login { username: String, password: String },
+    Resume { token: String },
+    Logout,
     
     // Gestione gruppi e messaggi
-    CreateGroup { name: String },
+    CreateGroup {
+        name: String,
+        #[serde(default)]
+        visibility: GroupVisibility,
+    },
     JoinGroup { group_name: String },
     LeaveGroup { group_name: String },
     QuitGroup,
     InviteUser { username: String, group_name: String },
-    SendMessage { content: String, group_name: String },
+    ListInvites,
+    AcceptInvite { group_name: String },
+    DeclineInvite { group_name: String },
+    PromoteMember { group_name: String, username: String },
+    DemoteMember { group_name: String, username: String },
+    KickMember { group_name: String, username: String },
+    /// Ban per duration_secs secondi (None = permanente)
+    BanMember { group_name: String, username: String, duration_secs: Option<i64> },
+    UnbanMember { group_name: String, username: String },
+    /// Apre la conversazione diretta con un utente; poi ci si riferisce a essa come "@username"
+    OpenDirect { username: String },
+    ListDirects,
+    SendMessage {
+        content: String,
+        group_name: String,
+        /// ID (o ID breve) del messaggio a cui si risponde
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
+    /// message_id può essere anche un prefisso univoco dell'ID (l'ID breve mostrato dal client)
+    EditMessage { message_id: String, content: String },
+    DeleteMessage { message_id: String },
+    /// Un messaggio con tutte le sue risposte
+    FetchThread { message_id: String },
+    React { message_id: String, emoji: String },
+    Unreact { message_id: String, emoji: String },
+    /// Menzioni non ancora lette; dopo la risposta vengono segnate come lette
+    ListMentions,
+    FetchHistory {
+        group_name: String,
+        before: Option<String>,
+        limit: u32,
+        #[serde(default)]
+        after: Option<String>,
+    },
+    SearchMessages { query: String, group_name: Option<String>, limit: u32 },
     ListGroups,
     ListUsers,
     ListGroupUsers { group_name: String },
+    ListPublicGroups,
+    SetGroupVisibility { group_name: String, visibility: GroupVisibility },
+    /// Rinomina il gruppo mantenendo lo stesso ID
+    RenameGroup { group_name: String, new_name: String },
+    /// None rimuove l'argomento
+    SetGroupTopic { group_name: String, topic: Option<String> },
+    /// None rimuove la descrizione
+    SetGroupDescription { group_name: String, description: Option<String> },
+    /// Rende il gruppo di sola lettura e lo nasconde da ListGroups
+    ArchiveGroup { group_name: String },
+    UnarchiveGroup { group_name: String },
+    /// Elimina il gruppo con tutti i suoi messaggi
+    DeleteGroup { group_name: String },
+    ListArchivedGroups,
     GoHome,
     
     // Utilità
@@ -25,16 +136,48 @@ pub enum ProtocolMessage {
     Quit,
 
     // Risposte dal server
-    AuthResult { success: bool, user_id: Option<UserId>, message: String },
+    AuthResult {
+        success: bool,
+        user_id: Option<UserId>,
+        message: String,
+        #[serde(default)]
+        session_token: Option<String>,
+    },
+    SessionResumed { user_id: UserId, group: Option<Group>, recent_messages: Vec<ChatMessage> },
     GroupCreated { group: Group },
     GroupJoined { group: Group, recent_messages: Vec<ChatMessage> },
     GroupLeft,
     GroupQuit,
     UserInvited { username: String },
+    InviteList { invites: Vec<GroupInvite> },
+    InviteReceived { invite: GroupInvite },
+    /// L'utente è stato espulso (o bandito) da un gruppo da un admin
+    RemovedFromGroup { group_name: String, removed_by: String, banned: bool, banned_until: Option<DateTime<Utc>> },
     MessageReceived { message: Message, recent_messages: Vec<ChatMessage> },
     ReloadMessages { recent_messages: Vec<ChatMessage> },
+    NewMessage { group: String, message: ChatMessage },
+    /// Un messaggio già inviato è stato modificato o eliminato
+    MessageUpdated { group: String, message: ChatMessage },
+    /// Le reazioni a un messaggio sono cambiate
+    ReactionsUpdated { group: String, message_id: String, reactions: Vec<ReactionCount> },
+    /// L'utente è stato menzionato con @username in un messaggio, ovunque si trovi
+    Mentioned { group: String, message: ChatMessage },
+    MentionList { mentions: Vec<Mention> },
+    HistoryPage { messages: Vec<ChatMessage>, has_more: bool },
+    /// Il primo messaggio è quello richiesto, seguito dalle risposte in ordine cronologico
+    Thread { group: String, messages: Vec<ChatMessage> },
+    SearchResults { query: String, results: Vec<SearchResult> },
     GroupListResponse { groups: Vec<Group> },
+    PublicGroupList { groups: Vec<GroupSummary> },
+    DirectList { conversations: Vec<DirectConversation> },
     UserListResponse { users: Vec<String> },
+    GroupMemberList { group_name: String, members: Vec<GroupMember> },
+    /// Nome, argomento o descrizione del gruppo sono cambiati (previous_name è il nome prima della modifica)
+    GroupUpdated { previous_name: String, group: Group },
+    /// Il gruppo è stato archiviato: chi si trovava nel gruppo torna nella home
+    GroupArchived { group_name: String, archived_by: String },
+    /// Il gruppo è stato eliminato: chi si trovava nel gruppo torna nella home
+    GroupDeleted { group_name: String, deleted_by: String },
     Error { message: String },
     Ok { message: String },
     
@@ -51,6 +194,45 @@ pub enum ProtocolMessage {
     Pong,
 }
 
+/// Frame scambiato sul wire: un ProtocolMessage con un request_id opzionale.
+/// Il server ripete il request_id della richiesta nella risposta; i messaggi senza
+/// request_id sono eventi non richiesti (es. NewMessage).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: ProtocolMessage,
+}
+
+impl Envelope {
+    pub fn new(request_id: Option<String>, message: ProtocolMessage) -> Self {
+        Self { request_id, message }
+    }
+
+    /// Crea un evento (messaggio senza request_id)
+    pub fn event(message: ProtocolMessage) -> Self {
+        Self { request_id: None, message }
+    }
+
+    /// Serializza l'envelope con il delimitatore di fine messaggio
+    pub fn to_wire_format(&self) -> ProtocolResult<String> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| ProtocolError::SerializationError(e.to_string()))?;
+        Ok(format!("{}\n", json))
+    }
+
+    /// Parsifica un frame dal formato wire.
+    /// Accetta anche i frame della versione 1, senza envelope.
+    pub fn from_wire_format(data: &str) -> ProtocolResult<Self> {
+        let trimmed = data.trim();
+        match serde_json::from_str::<Envelope>(trimmed) {
+            Ok(envelope) => Ok(envelope),
+            Err(_) => ProtocolMessage::from_json(trimmed).map(Envelope::event),
+        }
+    }
+}
+
 /// Risultato della serializzazione/deserializzazione dei messaggi
 pub type ProtocolResult<T> = Result<T, ProtocolError>;
 
@@ -78,6 +260,45 @@ impl std::error::Error for ProtocolError {}
 
 /// Funzioni di utilità per il protocollo
 impl ProtocolMessage {
+    /// Funzionalità che deve essere stata negoziata per poter inviare questo messaggio
+    /// (o, per gli eventi inviati dal server, per poterlo ricevere)
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            ProtocolMessage::FetchHistory { .. } => Some(capabilities::HISTORY),
+            ProtocolMessage::SearchMessages { .. } => Some(capabilities::SEARCH),
+            ProtocolMessage::Resume { .. } | ProtocolMessage::Logout => Some(capabilities::SESSIONS),
+            ProtocolMessage::PromoteMember { .. }
+            | ProtocolMessage::DemoteMember { .. }
+            | ProtocolMessage::KickMember { .. }
+            | ProtocolMessage::BanMember { .. }
+            | ProtocolMessage::UnbanMember { .. } => Some(capabilities::ROLES),
+            ProtocolMessage::OpenDirect { .. } | ProtocolMessage::ListDirects => Some(capabilities::DIRECT),
+            ProtocolMessage::ListPublicGroups | ProtocolMessage::SetGroupVisibility { .. } => Some(capabilities::BROWSE),
+            ProtocolMessage::CreateGroup { visibility: GroupVisibility::Private, .. } => Some(capabilities::BROWSE),
+            ProtocolMessage::RenameGroup { .. }
+            | ProtocolMessage::SetGroupTopic { .. }
+            | ProtocolMessage::SetGroupDescription { .. } => Some(capabilities::METADATA),
+            ProtocolMessage::ArchiveGroup { .. }
+            | ProtocolMessage::UnarchiveGroup { .. }
+            | ProtocolMessage::DeleteGroup { .. }
+            | ProtocolMessage::ListArchivedGroups => Some(capabilities::ARCHIVE),
+            ProtocolMessage::EditMessage { .. } | ProtocolMessage::DeleteMessage { .. } => Some(capabilities::EDIT),
+            ProtocolMessage::SendMessage { reply_to: Some(_), .. } | ProtocolMessage::FetchThread { .. } => Some(capabilities::THREADS),
+            ProtocolMessage::React { .. } | ProtocolMessage::Unreact { .. } => Some(capabilities::REACTIONS),
+            ProtocolMessage::ListMentions => Some(capabilities::MENTIONS),
+
+            // Eventi inviati dal server di propria iniziativa
+            ProtocolMessage::RemovedFromGroup { .. } => Some(capabilities::ROLES),
+            ProtocolMessage::GroupUpdated { .. } => Some(capabilities::METADATA),
+            ProtocolMessage::GroupArchived { .. } | ProtocolMessage::GroupDeleted { .. } => Some(capabilities::ARCHIVE),
+            ProtocolMessage::MessageUpdated { .. } => Some(capabilities::EDIT),
+            ProtocolMessage::ReactionsUpdated { .. } => Some(capabilities::REACTIONS),
+            ProtocolMessage::Mentioned { .. } => Some(capabilities::MENTIONS),
+            ProtocolMessage::InviteReceived { .. } => Some(capabilities::INVITES),
+            _ => None,
+        }
+    }
+
     /// Serializza il messaggio in JSON
     pub fn to_json(&self) -> ProtocolResult<String> {
         serde_json::to_string(self)
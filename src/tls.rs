@@ -0,0 +1,263 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read certificates from '{}': {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificate found in '{}'", path.display()));
+    }
+    Ok(certificates)
+}
+
+/// Impronta SHA-256 di un certificato, in esadecimale separato da ':' (es. "AB:CD:...")
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Normalizza un'impronta scritta dall'utente: accetta maiuscole/minuscole, con o senza ':'
+pub fn parse_fingerprint(value: &str) -> Result<String, String> {
+    let hex: String = value.chars().filter(|c| *c != ':').collect::<String>().to_uppercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid fingerprint '{}': expected a SHA-256 hash (64 hex digits)", value));
+    }
+    Ok(hex.as_bytes().chunks(2).map(|pair| String::from_utf8_lossy(pair).into_owned()).collect::<Vec<_>>().join(":"))
+}
+
+/// Configurazione TLS del server a partire da certificato e chiave privata in formato PEM.
+/// Restituisce anche l'impronta del certificato, da comunicare ai client che vogliono fissarla.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<(Arc<ServerConfig>, String), String> {
+    let certificates = load_certificates(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("Cannot read private key from '{}': {}", key_path.display(), e))?;
+    let certificate_fingerprint = fingerprint(&certificates[0]);
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+    Ok((Arc::new(config), certificate_fingerprint))
+}
+
+/// Come il client decide se fidarsi del certificato del server
+#[derive(Debug, Clone)]
+pub enum ServerTrust {
+    /// Certificato firmato da una delle CA contenute nel file PEM
+    CaFile(std::path::PathBuf),
+    /// Certificato con esattamente questa impronta SHA-256 (es. autofirmato)
+    Fingerprint(String),
+}
+
+/// Configurazione TLS del client
+pub fn client_config(trust: &ServerTrust) -> Result<Arc<ClientConfig>, String> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let config = match trust {
+        ServerTrust::CaFile(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(path)? {
+                roots.add(certificate)
+                    .map_err(|e| format!("Invalid CA certificate in '{}': {}", path.display(), e))?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        ServerTrust::Fingerprint(expected) => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(FingerprintVerifier {
+                expected: parse_fingerprint(expected)?,
+                provider: provider(),
+            }))
+            .with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Accetta solo il certificato con l'impronta attesa, senza controllare CA, nome o scadenza.
+/// Le firme dell'handshake vengono comunque verificate con la chiave del certificato.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    expected: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        if actual == self.expected {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!("Server certificate fingerprint {} does not match the pinned one", actual)))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Stato TLS condiviso tra la metà in lettura e quella in scrittura di una connessione bloccante
+struct TlsShared {
+    connection: Mutex<ClientConnection>,
+    socket: TcpStream,
+}
+
+impl TlsShared {
+    /// Scrive sul socket i record TLS in attesa di essere inviati
+    fn flush_records(&self, connection: &mut ClientConnection) -> io::Result<()> {
+        while connection.wants_write() {
+            connection.write_tls(&mut &self.socket)?;
+        }
+        Ok(())
+    }
+}
+
+/// Metà in lettura di una connessione TLS: la lettura dal socket avviene senza tenere il lock,
+/// così un thread può restare in attesa di dati mentre un altro invia
+pub struct TlsReader {
+    shared: Arc<TlsShared>,
+    socket: TcpStream,
+}
+
+/// Metà in scrittura di una connessione TLS
+pub struct TlsWriter {
+    shared: Arc<TlsShared>,
+}
+
+/// Esegue l'handshake TLS su un socket già connesso e lo divide in lettura e scrittura
+pub fn connect(socket: TcpStream, config: Arc<ClientConfig>, server_name: &str) -> io::Result<(TlsReader, TlsWriter)> {
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid TLS server name '{}'", server_name)))?;
+    let mut connection = ClientConnection::new(config, name).map_err(io::Error::other)?;
+
+    let mut handshake_socket = socket.try_clone()?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut handshake_socket)?;
+    }
+
+    let shared = Arc::new(TlsShared {
+        connection: Mutex::new(connection),
+        socket: socket.try_clone()?,
+    });
+    Ok((TlsReader { shared: Arc::clone(&shared), socket }, TlsWriter { shared }))
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0u8; 16 * 1024];
+        loop {
+            {
+                let mut connection = self.shared.connection.lock().unwrap();
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+            }
+
+            // Nessun dato in chiaro disponibile: attende nuovi record dal server
+            let read = self.socket.read(&mut incoming)?;
+            let mut connection = self.shared.connection.lock().unwrap();
+            let mut records = &incoming[..read];
+            loop {
+                // Con zero byte read_tls segnala la chiusura del socket
+                connection.read_tls(&mut records)?;
+                connection.process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if records.is_empty() {
+                    break;
+                }
+            }
+            self.shared.flush_records(&mut connection)?;
+        }
+    }
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        let written = connection.writer().write(buf)?;
+        self.shared.flush_records(&mut connection)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        connection.writer().flush()?;
+        self.shared.flush_records(&mut connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_SHA256: &str = "E3:B0:C4:42:98:FC:1C:14:9A:FB:F4:C8:99:6F:B9:24:27:AE:41:E4:64:9B:93:4C:A4:95:99:1B:78:52:B8:55";
+
+    #[test]
+    fn fingerprint_is_colon_separated_sha256() {
+        assert_eq!(fingerprint(b""), EMPTY_SHA256);
+    }
+
+    #[test]
+    fn fingerprints_are_normalized() {
+        assert_eq!(parse_fingerprint(EMPTY_SHA256).unwrap(), EMPTY_SHA256);
+        assert_eq!(parse_fingerprint(&EMPTY_SHA256.to_lowercase()).unwrap(), EMPTY_SHA256);
+        assert_eq!(parse_fingerprint(&EMPTY_SHA256.replace(':', "")).unwrap(), EMPTY_SHA256);
+    }
+
+    #[test]
+    fn malformed_fingerprints_are_rejected() {
+        let hex = EMPTY_SHA256.replace(':', "");
+        assert!(parse_fingerprint("").is_err());
+        assert!(parse_fingerprint(&hex[..62]).is_err());
+        assert!(parse_fingerprint(&format!("{}00", hex)).is_err());
+        assert!(parse_fingerprint(&format!("{}ZZ", &hex[..62])).is_err());
+        assert!(parse_fingerprint(&format!("{}é", &hex[..63])).is_err());
+    }
+}